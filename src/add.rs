@@ -4,6 +4,7 @@
 #![allow(clippy::doc_markdown)]
 
 use crate::config;
+use crate::encoding;
 use crate::utils::CHEZMOI_AUTO_SOURCE_VERSION;
 use crate::utils::Chezmoi;
 use crate::utils::ChezmoiVersion;
@@ -126,7 +127,7 @@ fn add_with_script(
         }
     };
     // Add while respecting filtering directives
-    filtered_add(&data_path, &src_path, None, status_out)?;
+    filtered_add(chezmoi, &data_path, &src_path, None, status_out)?;
 
     // Remove the temporary file that chezmoi added
     std::fs::remove_file(src_path)?;
@@ -137,11 +138,13 @@ fn add_with_script(
 
 /// Add and handle filtering directives (add:remove, add:hide and ignore)
 ///
+/// * `chezmoi`: Used to find the source root to resolve includes relative to
 /// * `target_path`: Path to write to
 /// * `src_path`: Path to actually read file data from
 /// * `script_path`: Path to modify script (if it exists)
 /// * `status_out`: Where to write status messages
 fn filtered_add(
+    chezmoi: &impl Chezmoi,
     target_path: &Utf8Path,
     src_path: &Utf8Path,
     script_path: Option<&Utf8Path>,
@@ -151,33 +154,48 @@ fn filtered_add(
         std::fs::read(src_path).context("Failed to load data from file we are adding")?;
 
     // If we are updating an existing script, run the contents through the filtering
-    let mut file_contents = if let Some(sp) = script_path {
+    let file_contents = if let Some(sp) = script_path {
         _ = writeln!(
             status_out,
             "Has existing modify script, parsing to check for filtering..."
         );
         let config_data = std::fs::read_to_string(sp).context("Failed to load modify script")?;
-        internal_filter(&config_data, &file_contents)?
+        let include_root = match chezmoi.source_root()? {
+            Some(root) => root,
+            None => sp.parent().context("Couldn't extract directory")?.into(),
+        };
+        internal_filter(&config_data, sp, &include_root, &file_contents)?
     } else {
-        file_contents
+        encoding::ensure_newline(file_contents)?
     };
 
-    if !file_contents.ends_with(b"\n") {
-        file_contents.push(b'\n');
-    }
-
     _ = writeln!(status_out, "Writing out file data");
     std::fs::write(target_path, file_contents)?;
     Ok(())
 }
 
 /// Perform internal filtering using add:hide and add:remove (modern filtering)
-fn internal_filter(config_data: &str, contents: &[u8]) -> anyhow::Result<Vec<u8>> {
-    let config = config::parse_for_add(config_data)?;
-    let mut file = std::io::Cursor::new(contents);
-    let result = filter_ini(&mut file, &config.mutations)?;
-    let s: String = itertools::intersperse(result, "\n".into()).collect();
-    Ok(s.as_bytes().into())
+fn internal_filter(
+    config_data: &str,
+    script_path: &Utf8Path,
+    include_root: &Utf8Path,
+    contents: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let config = config::parse_for_add(config_data, script_path, include_root)?;
+    let (contents, mut format) = encoding::decode(contents, config.encoding)
+        .context("Failed to decode the file being added")?;
+    if let Some(line_ending) = config.line_endings {
+        format.line_ending = line_ending;
+    }
+    let contents = config.renames.apply(&contents);
+    let actions = config.mutations.build(&contents)?;
+    let mut file = std::io::Cursor::new(contents.as_bytes());
+    let result = filter_ini(&mut file, &actions)?;
+    let mut s: String = itertools::intersperse(result, "\n".into()).collect();
+    if !s.ends_with('\n') {
+        s.push('\n');
+    }
+    encoding::encode(&s, format)
 }
 
 /// Create a modify script if one doesn't exist
@@ -221,7 +239,8 @@ enum ChezmoiState {
     },
 }
 
-fn recurse_files(path: &Utf8Path, buf: &mut Vec<Utf8PathBuf>) -> anyhow::Result<()> {
+/// Recursively collect all files below `path`
+pub(crate) fn recurse_files(path: &Utf8Path, buf: &mut Vec<Utf8PathBuf>) -> anyhow::Result<()> {
     let entries = Utf8Path::read_dir_utf8(path)?;
 
     for entry in entries {
@@ -347,6 +366,7 @@ pub(crate) fn add_file(
                 "Action: Updating existing .src.ini file for {script_path}."
             );
             filtered_add(
+                chezmoi,
                 data_path.as_ref(),
                 path,
                 Some(script_path.as_ref()),
@@ -97,6 +97,145 @@ const FILTER_TESTS: &[FilterTest] = &[
             "
         ),
     },
+    FilterTest {
+        cfg: indoc!(
+            r#"
+            source auto
+
+            rename "General" "fontSize" to "Appearance" "font_size"
+            rename section "Old" to "New"
+            add:remove "New" "secret"
+            "#
+        ),
+        input: indoc!(
+            r"
+            [General]
+            fontSize=12
+            name=test
+
+            [Old]
+            a=b
+            secret=c
+            "
+        ),
+        expected: indoc!(
+            r"
+            [General]
+            name=test
+
+            [New]
+            a=b
+            [Appearance]
+            font_size=12
+            "
+        ),
+    },
+    FilterTest {
+        cfg: indoc!(
+            r#"
+            source auto
+
+            if os "no-such-os" {
+                add:remove "a" "b"
+            } else {
+                add:remove "a" "c"
+            }
+            "#
+        ),
+        input: indoc!(
+            r"
+            [a]
+            b=c
+            c=d
+            "
+        ),
+        expected: indoc!(
+            r"
+            [a]
+            b=c
+            c=d
+            "
+        ),
+    },
+    FilterTest {
+        cfg: indoc!(
+            r#"
+            source auto
+
+            add:conditions all-branches
+            if os "no-such-os" {
+                add:remove "a" "b"
+            } else {
+                add:remove "a" "c"
+            }
+            "#
+        ),
+        input: indoc!(
+            r"
+            [a]
+            b=c
+            c=d
+            e=f
+            "
+        ),
+        expected: indoc!(
+            r"
+            [a]
+            e=f
+            "
+        ),
+    },
+    FilterTest {
+        cfg: indoc!(
+            r#"
+            source auto
+
+            add:remove regex "a" "Recent.*" value regex "^/home/"
+            add:hide "a" "token" value regex "."
+            "#
+        ),
+        input: indoc!(
+            r"
+            [a]
+            Recent1=/home/user/file
+            Recent2=/tmp/file
+            token=
+            "
+        ),
+        expected: indoc!(
+            r"
+            [a]
+            Recent2=/tmp/file
+            token=
+            "
+        ),
+    },
+    FilterTest {
+        cfg: indoc!(
+            r#"
+            source auto
+
+            ignore section "MainWindow" except "ToolBarsMovable"
+            "#
+        ),
+        input: indoc!(
+            r"
+            [MainWindow]
+            Height=1080
+            ToolBarsMovable=Disabled
+            [Other]
+            a=b
+            "
+        ),
+        expected: indoc!(
+            r"
+            [MainWindow]
+            ToolBarsMovable=Disabled
+            [Other]
+            a=b
+            "
+        ),
+    },
 ];
 
 fn get_dummy_file_contents(dummy_file_name: &str) -> String {
@@ -106,7 +245,12 @@ fn get_dummy_file_contents(dummy_file_name: &str) -> String {
 #[test]
 fn check_filtering() {
     for test_case in FILTER_TESTS {
-        let result = internal_filter(test_case.cfg, test_case.input.as_bytes());
+        let result = internal_filter(
+            test_case.cfg,
+            Utf8Path::new("modify_test"),
+            Utf8Path::new("."),
+            test_case.input.as_bytes(),
+        );
         dbg!(&result);
         let result = result.unwrap();
         assert_eq!(
@@ -53,8 +53,65 @@ fn style() -> impl Parser<Style> {
 #[derive(Debug, Bpaf)]
 #[bpaf(options, version)]
 pub enum ChmmArgs {
-    /// Process a single file (containing settings).
-    Process(#[bpaf(positional("FILE"), complete_shell(ShellComp::File{mask: None}))] Utf8PathBuf),
+    Process {
+        /// Fail if any ignore, remove or transform rule matches nothing in the
+        /// target or source file
+        #[bpaf(long("strict"))]
+        strict: bool,
+        /// Instead of the merged file, show what would change in the target
+        /// file, key by key
+        #[bpaf(long("diff"))]
+        diff: bool,
+        /// Don't use the cache of parsed modify scripts
+        #[bpaf(long("no-cache"))]
+        no_cache: bool,
+        /// Read the target file from PATH instead of stdin
+        #[bpaf(long("system"), argument("PATH"), complete_shell(ShellComp::File{mask: None}))]
+        system: Option<Utf8PathBuf>,
+        /// Process a single file (containing settings).
+        #[bpaf(positional("FILE"), complete_shell(ShellComp::File{mask: None}))]
+        file: Utf8PathBuf,
+    },
+    Explain {
+        /// Process a file (read from stdin as usual), but print each merged
+        /// line with where it came from and the directive that decided it
+        #[bpaf(long("explain"))]
+        _a: (),
+        /// Output the explanation as JSON
+        #[bpaf(long("json"))]
+        json: bool,
+        #[bpaf(positional("FILE"), complete_shell(ShellComp::File{mask: None}))]
+        file: Utf8PathBuf,
+    },
+    ProcessBatch {
+        /// Merge every entry of MANIFEST in one process. Each line has a
+        /// modify script, the target file and the file to write the result
+        /// to, separated by tabs. Use - to read the manifest from stdin.
+        #[bpaf(long("process-batch"), argument("MANIFEST"), complete_shell(ShellComp::File{mask: None}))]
+        manifest: Utf8PathBuf,
+    },
+    /// Merge a source file into a system file using a rules file, without
+    /// chezmoi
+    #[bpaf(command("merge"))]
+    Merge {
+        /// File with the rules (same syntax as a modify script, the source
+        /// directive is not needed)
+        #[bpaf(long("rules"), argument("RULES"), complete_shell(ShellComp::File{mask: None}))]
+        rules: Utf8PathBuf,
+        /// The file with the settings to merge in
+        #[bpaf(long("source"), argument("SRC"), complete_shell(ShellComp::File{mask: None}))]
+        source: Utf8PathBuf,
+        /// The current file on the system (treated as empty if it doesn't
+        /// exist)
+        #[bpaf(long("system"), argument("SYS"), complete_shell(ShellComp::File{mask: None}))]
+        system: Utf8PathBuf,
+        /// Write the result to OUT instead of stdout
+        #[bpaf(long("output"), argument("OUT"), complete_shell(ShellComp::File{mask: None}))]
+        output: Option<Utf8PathBuf>,
+        /// Atomically replace the system file with the result
+        #[bpaf(long("in-place"))]
+        in_place: bool,
+    },
     Add {
         /// Add a file to be tracked by chezmoi_modify_manager
         #[bpaf(short('a'), long("add"))]
@@ -88,6 +145,14 @@ pub enum ChmmArgs {
         #[bpaf(long("help-transforms"))]
         _a: (),
     },
+    Check {
+        /// Validate modify scripts without merging (checks all modify scripts
+        /// in the chezmoi source directory if no files are given)
+        #[bpaf(long("check"))]
+        _a: (),
+        #[bpaf(positional("FILE"), complete_shell(ShellComp::File{mask: None}))]
+        files: Vec<Utf8PathBuf>,
+    },
     Doctor {
         /// Perform environment sanity check
         #[bpaf(long("doctor"))]
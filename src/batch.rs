@@ -0,0 +1,254 @@
+//! Merging many modify scripts in one process (`--process-batch`).
+//!
+//! This is for scripts that render many targets at once, to avoid starting
+//! one process per file. The entries are merged in parallel. The regexes used
+//! when resolving the rules and the transforms are memoised and shared between
+//! them (see `config::cache`), but the mutations for ini-merge (including its
+//! regex set) are still built for each entry.
+
+use crate::MergeOptions;
+use crate::config::DirectiveCache;
+use crate::utils;
+use anyhow::Context;
+use anyhow::anyhow;
+use camino::Utf8PathBuf;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// A file to merge
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    /// The modify script
+    script: Utf8PathBuf,
+    /// The target file (the system state)
+    system: Utf8PathBuf,
+    /// Where to write the merged result
+    output: Utf8PathBuf,
+}
+
+/// Merge all entries of a manifest, reporting failed entries to
+/// `status_out`.
+pub(crate) fn process_batch(
+    manifest: &str,
+    cache: Option<&DirectiveCache>,
+    status_out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let entries = parse_manifest(manifest)?;
+    let results = run_parallel(&entries, |entry| process_entry(entry, cache));
+    let mut failed = 0;
+    for (entry, result) in entries.iter().zip(results) {
+        if let Err(err) = result {
+            failed += 1;
+            _ = writeln!(status_out, "{}: {err:#}", entry.script);
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(anyhow!(
+            "Failed to merge {failed} of {} entries",
+            entries.len()
+        )),
+    }
+}
+
+/// Parse a manifest: one entry per line, with the modify script, the target
+/// file and the output file separated by tabs. Blank lines and lines
+/// starting with `#` are ignored.
+fn parse_manifest(manifest: &str) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    for (idx, line) in manifest.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<_> = line.split('\t').collect();
+        let [script, system, output] = fields[..] else {
+            return Err(anyhow!(
+                "Manifest line {}: Expected 3 tab separated fields (script, target, output), \
+                 found {}",
+                idx + 1,
+                fields.len()
+            ));
+        };
+        entries.push(Entry {
+            script: script.into(),
+            system: system.into(),
+            output: output.into(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Merge a single entry. A missing target file is treated as empty, like
+/// chezmoi does.
+fn process_entry(entry: &Entry, cache: Option<&DirectiveCache>) -> anyhow::Result<()> {
+    let system = match std::fs::read(&entry.system) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => vec![],
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read system state from {}", entry.system));
+        }
+    };
+    let (mut c, system) = crate::load_script(&entry.script, None, cache, || system.as_slice())?;
+    // Each entry is a different script, so the environment can't say where
+    // its source file is
+    c.resolve_auto_from_path();
+    let output = crate::merge_file(c, &entry.script, &system, MergeOptions::default())?;
+    utils::write_atomic(&entry.output, &output)
+}
+
+/// Apply `f` to all items using a thread per CPU, returning the results in
+/// the same order as the items.
+fn run_parallel<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let threads = std::thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .min(items.len());
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, R)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = vec![];
+                    loop {
+                        let idx = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(idx) else {
+                            break;
+                        };
+                        done.push((idx, f(item)));
+                    }
+                    done
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("Worker thread panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(idx, _)| *idx);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn check_parse_manifest() {
+        let entries = parse_manifest("# Comment\n\na b\tsys\tout\n").unwrap();
+        assert_eq!(
+            entries,
+            vec![Entry {
+                script: "a b".into(),
+                system: "sys".into(),
+                output: "out".into(),
+            }]
+        );
+        let err = parse_manifest("a\tb\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Manifest line 1: Expected 3 tab separated fields (script, target, output), found 2"
+        );
+    }
+
+    #[test]
+    fn check_run_parallel() {
+        let items: Vec<_> = (0..100).collect();
+        assert_eq!(
+            run_parallel(&items, |x| x * 2),
+            items.iter().map(|x| x * 2).collect::<Vec<_>>()
+        );
+        let empty: &[i32] = &[];
+        assert!(run_parallel(empty, |x| *x).is_empty());
+    }
+
+    #[test]
+    fn check_process_batch() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        let mut lines = vec![];
+        for name in ["a", "b"] {
+            std::fs::write(
+                root.join(format!("modify_{name}")),
+                "source auto-path\nignore \"s\" \"kept\"\n",
+            )
+            .unwrap();
+            std::fs::write(root.join(format!("{name}.src.ini")), "[s]\nkept=1\nk=2\n").unwrap();
+            std::fs::write(root.join(format!("{name}.ini")), "[s]\nkept=3\nk=1\n").unwrap();
+            lines.push(format!(
+                "{root}/modify_{name}\t{root}/{name}.ini\t{root}/{name}.out"
+            ));
+        }
+        let mut status = vec![];
+        process_batch(&lines.join("\n"), None, &mut status).unwrap();
+        assert_eq!(status, b"");
+        for name in ["a", "b"] {
+            assert_eq!(
+                std::fs::read_to_string(root.join(format!("{name}.out"))).unwrap(),
+                "[s]\nkept=3\nk=2\n"
+            );
+        }
+
+        lines.push(format!("{root}/modify_c\t{root}/c.ini\t{root}/c.out"));
+        let err = process_batch(&lines.join("\n"), None, &mut status).unwrap_err();
+        assert_eq!(err.to_string(), "Failed to merge 1 of 3 entries");
+        assert!(
+            String::from_utf8(status)
+                .unwrap()
+                .starts_with(&format!("{root}/modify_c: Failed to load"))
+        );
+    }
+
+    #[test]
+    fn check_process_batch_source_auto() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        let mut lines = vec![];
+        for name in ["a", "b"] {
+            std::fs::write(root.join(format!("modify_{name}")), "source auto\n").unwrap();
+            std::fs::write(
+                root.join(format!("{name}.src.ini")),
+                format!("[s]\nk={name}\n"),
+            )
+            .unwrap();
+            std::fs::write(root.join(format!("{name}.ini")), "[s]\nk=1\n").unwrap();
+            lines.push(format!(
+                "{root}/modify_{name}\t{root}/{name}.ini\t{root}/{name}.out"
+            ));
+        }
+        let mut status = vec![];
+        process_batch(&lines.join("\n"), None, &mut status).unwrap();
+        assert_eq!(status, b"");
+        for name in ["a", "b"] {
+            assert_eq!(
+                std::fs::read_to_string(root.join(format!("{name}.out"))).unwrap(),
+                format!("[s]\nk={name}\n")
+            );
+        }
+    }
+
+    #[test]
+    fn check_process_batch_on_error() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        // The source file is missing
+        std::fs::write(
+            root.join("modify_a"),
+            "source auto-path\non-error passthrough\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("a.ini"), "[s]\nk=1\n").unwrap();
+        let manifest = format!("{root}/modify_a\t{root}/a.ini\t{root}/a.out");
+        let mut status = vec![];
+        process_batch(&manifest, None, &mut status).unwrap();
+        assert_eq!(status, b"");
+        assert_eq!(
+            std::fs::read_to_string(root.join("a.out")).unwrap(),
+            "[s]\nk=1\n"
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//! Validation of modify scripts without performing a merge
+
+use crate::add::recurse_files;
+use crate::config;
+use crate::utils::Chezmoi;
+use anyhow::Context;
+use anyhow::anyhow;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use std::io::Write;
+
+/// Check modify scripts for problems.
+///
+/// If no files are given, all modify scripts using `chezmoi_modify_manager` in
+/// the chezmoi source directory are checked.
+pub(crate) fn check(
+    chezmoi: &impl Chezmoi,
+    files: &[Utf8PathBuf],
+    status_out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let (source_root, files) = if files.is_empty() {
+        let root = chezmoi
+            .source_root()?
+            .context("Failed to find chezmoi source directory")?;
+        let files = find_scripts(&root)?;
+        (Some(root), files)
+    } else {
+        // Don't require chezmoi when checking explicitly given files (e.g.
+        // from a git hook), it is only needed to resolve includes.
+        (chezmoi.source_root().ok().flatten(), files.to_vec())
+    };
+
+    let mut failed = 0;
+    for file in &files {
+        let include_root = match source_root {
+            Some(ref root) => root.clone(),
+            None => config::include_root_from_env(file),
+        };
+        let problems = check_script(file, &include_root);
+        if problems.is_empty() {
+            _ = writeln!(status_out, "{file}: OK");
+        } else {
+            failed += 1;
+            for problem in problems {
+                _ = writeln!(status_out, "{file}: {problem:#}");
+            }
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        _ => Err(anyhow!(
+            "Found problems in {failed} of {} modify scripts",
+            files.len()
+        )),
+    }
+}
+
+/// Find all modify scripts that use `chezmoi_modify_manager`
+fn find_scripts(root: &Utf8Path) -> anyhow::Result<Vec<Utf8PathBuf>> {
+    let mut files = vec![];
+    recurse_files(root, &mut files)?;
+    let mut scripts = vec![];
+    for file in files {
+        let is_modify_script = file
+            .file_name()
+            .is_some_and(|name| name.starts_with("modify_"));
+        if !is_modify_script {
+            continue;
+        }
+        // Other modify scripts may exist, only check the ones we handle
+        let contents = std::fs::read_to_string(&file).unwrap_or_default();
+        let first_line = contents.lines().next().unwrap_or_default();
+        if first_line.starts_with("#!") && first_line.contains("chezmoi_modify_manager") {
+            scripts.push(file);
+        }
+    }
+    scripts.sort();
+    Ok(scripts)
+}
+
+/// Check a single modify script, returning all problems found.
+///
+/// This parses the script in both merge and add mode (compiling all regular
+/// expressions and constructing all transforms, in all branches of
+/// conditional blocks), and verifies that the source file exists. Regexes
+/// overlapping on keys of the source file are logged as warnings.
+fn check_script(script_path: &Utf8Path, include_root: &Utf8Path) -> Vec<anyhow::Error> {
+    let src = match std::fs::read_to_string(script_path) {
+        Ok(src) => src,
+        Err(err) => return vec![anyhow!(err).context("Failed to load script")],
+    };
+    let mut problems = vec![];
+    let config = match config::parse_for_merge(&src, script_path, include_root) {
+        Ok(config) => config,
+        // Syntax errors, invalid regexes and unknown transforms (in any
+        // branch of conditional blocks) would only be reported twice below
+        Err(err) => return vec![err],
+    };
+    let src_path = config.static_source_path(script_path);
+    // Same for other problems building the rules. Rules for other machines
+    // should be valid too, so check all branches of conditional blocks.
+    // Overlapping regexes are warned about for the keys in the source file.
+    let source = src_path
+        .as_ref()
+        .ok()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .unwrap_or_default();
+    if let Err(err) =
+        config::parse_all_branches(&src, script_path, include_root).and_then(|mut rules| {
+            rules.report_overlaps();
+            rules.build("", &source)
+        })
+    {
+        return vec![err];
+    }
+    match src_path {
+        Ok(src_path) if src_path.is_file() => (),
+        Ok(src_path) => problems.push(anyhow!("Source file {src_path} does not exist")),
+        Err(err) => problems.push(err.context("Failed to find source file")),
+    }
+    if let Err(err) = config::parse_for_add(&src, script_path, include_root)
+        .and_then(|config| config.mutations.build(""))
+    {
+        problems.push(err.context("Invalid directives for --add"));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    fn find_problems(script: &str, create_source: bool) -> Vec<String> {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        let script_path = root.join("modify_test.ini");
+        std::fs::write(&script_path, script).unwrap();
+        if create_source {
+            std::fs::write(root.join("test.ini.src.ini"), "[a]\nb=c\n").unwrap();
+        }
+        check_script(&script_path, &root)
+            .into_iter()
+            .map(|err| format!("{err:#}"))
+            .collect()
+    }
+
+    #[test]
+    fn check_valid() {
+        let script =
+            "#!/usr/bin/env chezmoi_modify_manager\nsource auto\nignore regex \"a\" \"b.*\"\n";
+        assert_eq!(find_problems(script, true), Vec::<String>::new());
+    }
+
+    #[test]
+    fn check_missing_source() {
+        let script = "source auto\n";
+        let problems = find_problems(script, false);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("Source file "));
+        assert!(problems[0].ends_with("test.ini.src.ini does not exist"));
+    }
+
+    #[test]
+    fn check_invalid_regex() {
+        let problems = find_problems("source auto\nignore regex \"a\" \"b(\"\n", true);
+        assert_eq!(problems.len(), 1);
+        assert!(
+            problems[0]
+                .contains("modify_test.ini:2: Invalid regex \"a\" \"b(\": regex parse error")
+        );
+
+        let problems = find_problems(
+            "source auto\nadd:remove regex \"a\" \"b\" value regex \"(\"\n",
+            true,
+        );
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("modify_test.ini:2: Invalid value regex \"(\""));
+    }
+
+    #[test]
+    fn check_invalid_transform() {
+        let problems = find_problems(
+            "source auto\ntransform \"a\" \"b\" no-such-transform\n",
+            true,
+        );
+        assert_eq!(problems.len(), 1);
+        assert!(
+            problems[0]
+                .contains("modify_test.ini:2: Invalid transform specified: no-such-transform")
+        );
+    }
+
+    #[test]
+    fn check_included_location() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        let script_path = root.join("modify_test.ini");
+        std::fs::write(&script_path, "source auto\ninclude \"shared.rules\"\n").unwrap();
+        std::fs::write(root.join("shared.rules"), "\nremove regex \"a\" \"b[\"\n").unwrap();
+        std::fs::write(root.join("test.ini.src.ini"), "").unwrap();
+        let problems: Vec<_> = check_script(&script_path, &root)
+            .into_iter()
+            .map(|err| format!("{err:#}"))
+            .collect();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with(&format!(
+            "{script_path}:2: While processing included file {root}/shared.rules: \
+             {root}/shared.rules:2: Invalid regex \"a\" \"b[\""
+        )));
+    }
+
+    #[test]
+    fn check_all_branches() {
+        let script = indoc! {r#"
+            if hostname "no-such-host" {
+                source "other.ini"
+                ignore regex "s" "(unclosed"
+            } else {
+                source auto
+            }
+        "#};
+        let problems = find_problems(script, true);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("modify_test.ini:3: Invalid regex \"s\" \"(unclosed\""));
+
+        let script = indoc! {r#"
+            source auto
+            if os "no-such-os" {
+                transform "a" "b" no-such-transform
+            }
+        "#};
+        let problems = find_problems(script, true);
+        assert_eq!(problems.len(), 1);
+        assert!(
+            problems[0]
+                .contains("modify_test.ini:3: Invalid transform specified: no-such-transform")
+        );
+    }
+}
@@ -1,7 +1,20 @@
 //! Describes configuration file format
+use self::conditions::Mode;
+use self::diagnostics::ParseDiagnostic;
+use self::parser::AddConditions;
 use self::parser::Directive;
 use self::parser::Matcher;
-use crate::transforms::Transform;
+use self::parser::Strict;
+use self::parser::UnknownPolicy;
+use self::priority::Rank;
+use self::priority::Rule;
+use self::resolve::Resolver;
+use self::resolve::Unknown;
+use self::trace::TracingBuilder;
+use crate::encoding::Encoding;
+use crate::encoding::LineEnding;
+use crate::ini_state::IniState;
+use crate::rename::Renames;
 use anyhow::Context;
 use anyhow::anyhow;
 use camino::Utf8Path;
@@ -11,16 +24,33 @@ use ini_merge::filter::FilterActions;
 use ini_merge::filter::FilterActionsBuilder;
 use ini_merge::mutations::Action;
 use ini_merge::mutations::Mutations;
-use ini_merge::mutations::MutationsBuilder;
 use ini_merge::mutations::SectionAction;
-use ini_merge::mutations::transforms;
 use std::borrow::Cow;
-use std::collections::HashMap;
 use std::fmt::Debug;
-use std::str::FromStr;
 use winnow::Parser;
 
+mod cache;
+mod conditions;
+mod diagnostics;
+mod directive_cache;
 mod parser;
+mod priority;
+mod resolve;
+mod strict;
+mod trace;
+
+pub(crate) use self::diagnostics::Origin;
+pub(crate) use self::directive_cache::DirectiveCache;
+pub(crate) use self::parser::ConflictPolicy;
+pub(crate) use self::parser::OnError;
+pub(crate) use self::trace::Effect;
+pub(crate) use self::trace::Trace;
+
+/// Directives, with where each was written
+type Directives = Vec<(Origin, Directive)>;
+
+/// Files included by a config file, with their contents
+type Included = Vec<(Utf8PathBuf, String)>;
 
 /// Where to find the source file
 #[derive(Debug)]
@@ -46,6 +76,12 @@ where
 {
     pub(crate) source: Source,
     pub(crate) mutations: ActionType,
+    /// Renames to apply to the system state before merging or filtering
+    pub(crate) renames: Renames,
+    /// Encoding of the files (`None` to detect it from the byte order mark)
+    pub(crate) encoding: Option<Encoding>,
+    /// Line endings to write (`None` to detect them from the files)
+    pub(crate) line_endings: Option<LineEnding>,
 }
 
 impl<ActionType> Config<ActionType>
@@ -75,6 +111,33 @@ where
     }
 }
 
+impl<ActionType> Config<ActionType>
+where
+    ActionType: Debug,
+{
+    /// Compute the source path without relying on the environment chezmoi
+    /// provides when running modify scripts.
+    ///
+    /// Templated source paths can't be resolved without chezmoi, so these
+    /// (like `source auto`) are assumed to follow the default naming scheme.
+    pub(crate) fn static_source_path(&self, script_path: &Utf8Path) -> anyhow::Result<Utf8PathBuf> {
+        match self.source {
+            Source::Path(ref p) if !p.as_str().contains("{{") => Ok(p.clone()),
+            Source::Path(_) | Source::AutoEnv | Source::AutoPath => {
+                resolve_relative_path(script_path)
+            }
+        }
+    }
+
+    /// Resolve `source auto` relative to the script path rather than from
+    /// the environment, which only describes the script chezmoi is running.
+    pub(crate) fn resolve_auto_from_path(&mut self) {
+        if matches!(self.source, Source::AutoEnv) {
+            self.source = Source::AutoPath;
+        }
+    }
+}
+
 /// Resolve the data path relative to a known script path
 fn resolve_relative_path(script_path: &Utf8Path) -> anyhow::Result<Utf8PathBuf> {
     let script_name = script_path
@@ -89,31 +152,234 @@ fn resolve_relative_path(script_path: &Utf8Path) -> anyhow::Result<Utf8PathBuf>
     Ok(script_path.with_file_name(data_name))
 }
 
-/// Create a transformer based on name
-fn make_transformer(
-    transform: &str,
-    args: &HashMap<String, String>,
-) -> anyhow::Result<transforms::TransformerDispatch> {
-    Transform::from_str(transform)
-        .map_err(|err| anyhow!("Invalid transform specified: {transform}: {err}"))?
-        .construct(args)
+/// Find the directory that `include` directives are resolved relative to
+/// when running under chezmoi.
+///
+/// This is the chezmoi source directory if known, otherwise the directory
+/// the modify script is in.
+pub(crate) fn include_root_from_env(script_path: &Utf8Path) -> Utf8PathBuf {
+    match std::env::var("CHEZMOI_SOURCE_DIR") {
+        Ok(dir) => dir.into(),
+        Err(_) => script_path
+            .parent()
+            .map(Utf8Path::to_path_buf)
+            .unwrap_or_default(),
+    }
 }
 
-/// Parse directives for operation
-pub(crate) fn parse_for_merge(src: &str) -> anyhow::Result<Config<Mutations>> {
-    let result = parser::parse_config
+/// Parse a config file, recursively expanding any `include` directives
+fn load_directives(
+    src: &str,
+    script_path: &Utf8Path,
+    include_root: &Utf8Path,
+) -> anyhow::Result<Directives> {
+    Ok(load_directives_tracked(src, script_path, include_root)?.0)
+}
+
+/// Like [`load_directives`], but also returns the files that were included
+/// (directly or indirectly), with their contents.
+fn load_directives_tracked(
+    src: &str,
+    script_path: &Utf8Path,
+    include_root: &Utf8Path,
+) -> anyhow::Result<(Directives, Included)> {
+    let mut include_stack = vec![];
+    // The top level file might not exist on disk (e.g. in tests)
+    if let Ok(path) = script_path.canonicalize_utf8() {
+        include_stack.push(path);
+    }
+    let mut result = vec![];
+    let mut included = vec![];
+    expand_includes(
+        src,
+        script_path,
+        include_root,
+        &mut include_stack,
+        &mut included,
+        &mut result,
+    )?;
+    Ok((result, included))
+}
+
+/// Parse a single file, expanding includes into `result`.
+///
+/// The `include_stack` is the chain of files currently being expanded, used
+/// to detect include cycles.
+fn expand_includes(
+    src: &str,
+    file_path: &Utf8Path,
+    include_root: &Utf8Path,
+    include_stack: &mut Vec<Utf8PathBuf>,
+    included: &mut Included,
+    result: &mut Directives,
+) -> anyhow::Result<()> {
+    let directives = parser::parse_config
         .parse(src)
-        .map_err(|e| anyhow::format_err!("{e}"))?;
+        .map_err(|e| ParseDiagnostic::new(file_path, src, &e))?;
+    // Line numbers of currently open conditional blocks, and if they have
+    // seen an else
+    let mut open_blocks = vec![];
+    for (offset, directive) in directives {
+        let (line, _) = diagnostics::line_column(src, offset);
+        match directive {
+            Directive::If(_) => open_blocks.push((line, false)),
+            Directive::Else => match open_blocks.last_mut() {
+                Some((_, seen_else @ false)) => *seen_else = true,
+                Some((_, true)) => {
+                    return Err(anyhow!("{file_path}:{line}: Multiple else in if block"));
+                }
+                None => return Err(anyhow!("{file_path}:{line}: else without matching if")),
+            },
+            Directive::EndIf => {
+                open_blocks
+                    .pop()
+                    .ok_or_else(|| anyhow!("{file_path}:{line}: Unmatched closing brace"))?;
+            }
+            _ => (),
+        }
+        let Directive::Include(include) = directive else {
+            check_directive(&directive).map_err(|err| anyhow!("{file_path}:{line}: {err:#}"))?;
+            result.push((Origin::new(file_path, src, offset), directive));
+            continue;
+        };
+        let include_path = include_root.join(&include);
+        let canonical = include_path.canonicalize_utf8().with_context(|| {
+            format!(
+                "{file_path}:{line}: Failed to include \"{include}\" (looked at {include_path})"
+            )
+        })?;
+        if include_stack.contains(&canonical) {
+            let chain = include_stack
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|p| p.as_str())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(anyhow!(
+                "{file_path}:{line}: Include cycle detected: {chain}"
+            ));
+        }
+        let included_src = std::fs::read_to_string(&canonical).with_context(|| {
+            format!("{file_path}:{line}: Failed to load included file {include_path}")
+        })?;
+        included.push((canonical.clone(), included_src.clone()));
+        include_stack.push(canonical);
+        expand_includes(
+            &included_src,
+            &include_path,
+            include_root,
+            include_stack,
+            included,
+            result,
+        )
+        .with_context(|| {
+            format!("{file_path}:{line}: While processing included file {include_path}")
+        })?;
+        include_stack.pop();
+    }
+    if let Some((line, _)) = open_blocks.first() {
+        return Err(anyhow!("{file_path}:{line}: Unterminated if block"));
+    }
+    Ok(())
+}
 
-    let mut source = None;
-    let mut builder = MutationsBuilder::new();
+/// Check that the regexes of a directive are valid and that its transform
+/// exists, so that mistakes in the script are reported with their location
+/// (and never handled by `on-error`).
+///
+/// Regexes are only parsed, compiling them is left to ini-merge.
+fn check_directive(directive: &Directive) -> anyhow::Result<()> {
+    match directive {
+        Directive::Ignore(matcher)
+        | Directive::Remove(matcher)
+        | Directive::AddRemove(matcher)
+        | Directive::AddHide(matcher) => check_matcher(matcher),
+        Directive::Transform(matcher, transform, args) => {
+            check_matcher(matcher)?;
+            cache::transform(transform, args)?;
+            Ok(())
+        }
+        Directive::Priority(_, directive) => check_directive(directive),
+        _ => Ok(()),
+    }
+}
+
+/// Check the regexes of a matcher, see [`check_directive`]
+fn check_matcher(matcher: &Matcher) -> anyhow::Result<()> {
+    let check = |pattern: &str, describe: &dyn Fn() -> String| {
+        regex_syntax::Parser::new()
+            .parse(pattern)
+            .map(|_| ())
+            .with_context(describe)
+    };
+    match matcher {
+        Matcher::Section(_) | Matcher::Literal(_, _) => Ok(()),
+        Matcher::SectionRegex(section) => {
+            check(section, &|| format!("Invalid section regex \"{section}\""))
+        }
+        // Same format as ini-merge uses for regex actions
+        Matcher::Regex(section, key) => check(&format!("(?:{section})\0(?:{key})"), &|| {
+            format!("Invalid regex \"{section}\" \"{key}\"")
+        }),
+        Matcher::Value(matcher, value) => {
+            check_matcher(matcher)?;
+            check(value, &|| format!("Invalid value regex \"{value}\""))
+        }
+        Matcher::Except(matcher, exceptions) => {
+            check_matcher(matcher)?;
+            for exception in exceptions {
+                if let parser::KeyPattern::Regex(key) = exception {
+                    check(key, &|| format!("Invalid except regex \"{key}\""))?;
+                }
+            }
+            Ok(())
+        }
+    }
+}
 
-    // Build config object
-    for directive in result {
+/// Merge directives that depend on the contents of the files being merged.
+///
+/// These are turned into [`Mutations`] by [`MergeRules::build`] once the
+/// system and source states are known.
+#[derive(Debug)]
+pub(crate) struct MergeRules {
+    directives: Directives,
+    /// Report regexes that overlap, see [`MergeRules::report_overlaps`]
+    report_overlaps: bool,
+}
+
+/// Add directives that depend on the contents of the file being added.
+///
+/// These are turned into [`FilterActions`] by [`FilterRules::build`].
+#[derive(Debug)]
+pub(crate) struct FilterRules {
+    directives: Directives,
+}
+
+/// Directives common to merging and adding
+struct Common {
+    source: Source,
+    renames: Renames,
+    encoding: Option<Encoding>,
+    line_endings: Option<LineEnding>,
+}
+
+/// Extract the directives that are common to merging and adding (source,
+/// renames, encoding and line endings), returning the remaining directives.
+///
+/// A `source_override` takes precedence over any `source` directive, and
+/// makes it optional.
+fn extract_common(
+    directives: Directives,
+    source_override: Option<Source>,
+) -> anyhow::Result<(Common, Directives)> {
+    let mut source = None;
+    let mut renames = Renames::default();
+    let mut encoding = None;
+    let mut line_endings = None;
+    let mut remaining = vec![];
+    for (origin, directive) in directives {
         match directive {
-            Directive::WS => (),
-            // Not relevant for merging
-            Directive::AddRemove(_) | Directive::AddHide(_) => (),
             Directive::Source(src) => {
                 if source.is_some() {
                     return Err(anyhow!("Duplicate source directives not allowed!"));
@@ -132,133 +398,847 @@ pub(crate) fn parse_for_merge(src: &str) -> anyhow::Result<Config<Mutations>> {
                 }
                 source = Some(Source::AutoPath);
             }
-            Directive::Ignore(Matcher::Section(section)) => {
-                builder.add_section_literal_action(section, SectionAction::Ignore);
-            }
-            Directive::Ignore(Matcher::SectionRegex(section)) => {
-                builder.add_section_regex_action(section, SectionAction::Ignore);
-            }
-            Directive::Ignore(matcher) => {
-                add_merge_action(&mut builder, matcher, Action::Ignore);
-            }
-            Directive::Transform(matcher, transform, args) => {
-                let t = make_transformer(&transform, &args)?;
-                add_merge_action(&mut builder, matcher, Action::Transform(t));
-            }
-            Directive::Set {
+            Directive::RenameSection { from, to } => renames.add_section(from, to)?,
+            Directive::RenameKey {
                 section,
                 key,
-                value,
-                separator,
-            } => {
-                // Set is a transform under the hood, but needs special support
-                // to enable adding lines that don't exist. This is handled inside
-                // the mutations builder.
-                builder.add_setter(
-                    section,
-                    key,
-                    &value,
-                    &separator.unwrap_or_else(|| " = ".to_string()),
-                );
-            }
-            Directive::Remove(Matcher::Section(section)) => {
-                builder.add_section_literal_action(section, SectionAction::Delete);
-            }
-            Directive::Remove(matcher) => {
-                add_merge_action(&mut builder, matcher, Action::Delete);
-            }
-            Directive::NoWarnMultipleKeyMatches => {
-                builder.warn_on_multiple_matches(false);
-            }
+                new_section,
+                new_key,
+            } => renames.add_key(section, key, new_section, new_key)?,
+            Directive::Encoding(value) => encoding = value,
+            Directive::LineEndings(value) => line_endings = value,
+            directive => remaining.push((origin, directive)),
         }
     }
+    let common = Common {
+        source: source_override
+            .or(source)
+            .ok_or_else(|| anyhow!("No source directive found"))?,
+        renames,
+        encoding,
+        line_endings,
+    };
+    Ok((common, remaining))
+}
 
+/// Parse directives for operation
+pub(crate) fn parse_for_merge(
+    src: &str,
+    script_path: &Utf8Path,
+    include_root: &Utf8Path,
+) -> anyhow::Result<Config<MergeRules>> {
+    parse_merge_rules(src, script_path, include_root, None, None)
+}
+
+/// Like [`parse_for_merge`], but loads the directives from the cache if they
+/// are up to date (and stores them otherwise).
+pub(crate) fn parse_for_merge_cached(
+    src: &str,
+    script_path: &Utf8Path,
+    include_root: &Utf8Path,
+    cache: &DirectiveCache,
+) -> anyhow::Result<Config<MergeRules>> {
+    parse_merge_rules(src, script_path, include_root, None, Some(cache))
+}
+
+/// Parse a rules file for merging with an explicitly given source file (the
+/// `merge` subcommand). The rules file doesn't need a `source` directive.
+pub(crate) fn parse_for_standalone_merge(
+    src: &str,
+    rules_path: &Utf8Path,
+    include_root: &Utf8Path,
+    source: &Utf8Path,
+) -> anyhow::Result<Config<MergeRules>> {
+    parse_merge_rules(
+        src,
+        rules_path,
+        include_root,
+        Some(Source::Path(source.to_owned())),
+        None,
+    )
+}
+
+fn parse_merge_rules(
+    src: &str,
+    script_path: &Utf8Path,
+    include_root: &Utf8Path,
+    source_override: Option<Source>,
+    cache: Option<&DirectiveCache>,
+) -> anyhow::Result<Config<MergeRules>> {
+    let directives = match cache {
+        Some(cache) => cache.load_directives(src, script_path, include_root)?,
+        None => load_directives(src, script_path, include_root)?,
+    };
+    let result = conditions::resolve(directives, Mode::Evaluate)?;
+    let (common, directives) = extract_common(result, source_override)?;
     Ok(Config {
-        source: source.ok_or_else(|| anyhow!("No source directive found"))?,
-        mutations: builder.build()?,
+        source: common.source,
+        mutations: MergeRules {
+            directives,
+            report_overlaps: false,
+        },
+        renames: common.renames,
+        encoding: common.encoding,
+        line_endings: common.line_endings,
     })
 }
 
-/// Parse directives for operation
-pub(crate) fn parse_for_add(src: &str) -> Result<Config<FilterActions>, anyhow::Error> {
-    let result = parser::parse_config
-        .parse(src)
-        .map_err(|e| anyhow::format_err!("{e}"))?;
+/// Parse the merge rules of all branches of conditional blocks (for
+/// `--check`), so that rules only used on other machines are checked too.
+///
+/// The directives handled by [`extract_common`] are left out, as different
+/// branches may well set them differently.
+pub(crate) fn parse_all_branches(
+    src: &str,
+    script_path: &Utf8Path,
+    include_root: &Utf8Path,
+) -> anyhow::Result<MergeRules> {
+    let directives = load_directives(src, script_path, include_root)?;
+    let directives = conditions::resolve(directives, Mode::Add(AddConditions::AllBranches))?
+        .into_iter()
+        .filter(|(_, directive)| {
+            !matches!(
+                directive,
+                Directive::Source(_)
+                    | Directive::SourceAutoEnv
+                    | Directive::SourceAutoPath
+                    | Directive::RenameSection { .. }
+                    | Directive::RenameKey { .. }
+                    | Directive::Encoding(_)
+                    | Directive::LineEndings(_)
+            )
+        })
+        .collect();
+    Ok(MergeRules {
+        directives,
+        report_overlaps: false,
+    })
+}
 
-    let mut source = None;
-    let mut builder = FilterActionsBuilder::new();
+impl MergeRules {
+    /// Warn about keys matched by several regex rules when building. This
+    /// is too slow for the fast path, so it is only done for `--check` and
+    /// `--explain`.
+    pub(crate) fn report_overlaps(&mut self) {
+        self.report_overlaps = true;
+    }
 
-    // Build config object
-    for directive in result {
-        match directive {
-            Directive::WS => (),
-            Directive::AddHide(matcher) => {
-                add_filter_action(&mut builder, matcher, FilterAction::Replace("HIDDEN"));
-            }
-            Directive::AddRemove(matcher) | Directive::Ignore(matcher) => {
-                add_filter_action(&mut builder, matcher, FilterAction::Remove);
-            }
-            // Common
-            Directive::Source(src) => {
-                if source.is_some() {
-                    return Err(anyhow!("Duplicate source directives not allowed!"));
+    /// Report rules that match nothing in the system or source state.
+    ///
+    /// This is done according to the last `strict` directive, or as errors if
+    /// `force` is set (`--strict`). The `system` contents must already have
+    /// the renames applied.
+    pub(crate) fn check_unmatched(
+        &self,
+        force: bool,
+        script_path: &Utf8Path,
+        system: &str,
+        source: &str,
+    ) -> anyhow::Result<()> {
+        let directive_mode =
+            self.directives
+                .iter()
+                .rev()
+                .find_map(|(_, directive)| match directive {
+                    Directive::Strict(mode) => Some(*mode),
+                    _ => None,
+                });
+        let Some(mode) = force.then_some(Strict::Error).or(directive_mode) else {
+            return Ok(());
+        };
+        let system = IniState::new(system);
+        let source = IniState::new(source);
+        let unmatched = strict::unmatched(
+            self.directives.iter().map(|(_, directive)| directive),
+            &[&system, &source],
+        )?;
+        if unmatched.is_empty() {
+            return Ok(());
+        }
+        match mode {
+            Strict::Warn => {
+                for rule in unmatched {
+                    log::warn!("{script_path}: Rule matches nothing: `{rule}`");
                 }
-                source = Some(Source::Path(src.into()));
+                Ok(())
             }
-            Directive::SourceAutoEnv => {
-                if source.is_some() {
-                    return Err(anyhow!("Duplicate source directives not allowed!"));
+            Strict::Error => Err(anyhow!(
+                "{script_path}: Rules that match nothing in the target or source file:\n{}",
+                unmatched
+                    .iter()
+                    .map(|rule| format!("  {rule}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            )),
+        }
+    }
+
+    /// How to handle local changes that merging would overwrite (the last
+    /// `conflict-policy` directive, warn if there is none)
+    pub(crate) fn conflict_policy(&self) -> ConflictPolicy {
+        self.directives
+            .iter()
+            .rev()
+            .find_map(|(_, directive)| match directive {
+                Directive::ConflictPolicy(policy) => Some(*policy),
+                _ => None,
+            })
+            .unwrap_or(ConflictPolicy::Warn)
+    }
+
+    /// What to output if merging fails (the last `on-error` directive, fail
+    /// if there is none)
+    pub(crate) fn on_error(&self) -> OnError {
+        self.directives
+            .iter()
+            .rev()
+            .find_map(|(_, directive)| match directive {
+                Directive::OnError(policy) => Some(*policy),
+                _ => None,
+            })
+            .unwrap_or(OnError::Fail)
+    }
+
+    /// Whether to validate the merged result (the last `validate` directive,
+    /// on if there is none)
+    pub(crate) fn validate(&self) -> bool {
+        self.directives
+            .iter()
+            .rev()
+            .find_map(|(_, directive)| match directive {
+                Directive::Validate(enabled) => Some(*enabled),
+                _ => None,
+            })
+            .unwrap_or(true)
+    }
+
+    /// Build the mutations for merging.
+    ///
+    /// The `system` contents must already have the renames applied. Pass
+    /// empty strings to get the behaviour on a machine where the files don't
+    /// exist yet.
+    pub(crate) fn build(self, system: &str, source: &str) -> anyhow::Result<Mutations> {
+        Ok(self.build_traced(system, source)?.0)
+    }
+
+    /// Build the mutations, together with a [`Trace`] of which rule each
+    /// action came from (for `--explain`).
+    pub(crate) fn build_traced(
+        self,
+        system: &str,
+        source: &str,
+    ) -> anyhow::Result<(Mutations, Trace)> {
+        let system = IniState::new(system);
+        let source = IniState::new(source);
+        let states = [&system, &source];
+        let unknown_keys = self
+            .directives
+            .iter()
+            .rev()
+            .find_map(|(origin, directive)| match directive {
+                Directive::UnknownKeys(policy) => Some((*policy, origin.clone())),
+                _ => None,
+            });
+        let unknown_sections = self
+            .directives
+            .iter()
+            .rev()
+            .find_map(|(origin, directive)| match directive {
+                Directive::UnknownSections(policy) => Some((*policy, origin.clone())),
+                _ => None,
+            });
+        let (rules, policy) = priority::order(self.directives);
+        let mut resolver = Resolver::new(&states, policy);
+        if self.report_overlaps {
+            resolver.report_overlaps();
+        }
+        let mut builder = TracingBuilder::new();
+        // Overlapping regexes are reported by the resolver instead
+        builder.warn_on_multiple_matches(false);
+
+        for Rule {
+            rank,
+            origin,
+            directive,
+        } in rules
+        {
+            let rule = builder.rule(origin, &directive);
+            match directive {
+                // Includes are already expanded by load_directives()
+                Directive::WS | Directive::Include(_) => (),
+                // Conditionals are already resolved
+                Directive::If(_) | Directive::Else | Directive::EndIf => (),
+                // Handled by extract_common()
+                Directive::Source(_)
+                | Directive::SourceAutoEnv
+                | Directive::SourceAutoPath
+                | Directive::RenameSection { .. }
+                | Directive::RenameKey { .. }
+                | Directive::Encoding(_)
+                | Directive::LineEndings(_) => (),
+                // Handled by priority::order()
+                Directive::Priority(_, _) | Directive::MatchPolicy(_) => (),
+                // Handled by check_unmatched()
+                Directive::Strict(_) => (),
+                // Handled by conflict_policy(), on_error() and validate()
+                Directive::ConflictPolicy(_) | Directive::OnError(_) | Directive::Validate(_) => (),
+                // Handled after all other rules
+                Directive::UnknownKeys(_) | Directive::UnknownSections(_) => (),
+                // Not relevant for merging
+                Directive::AddRemove(_) | Directive::AddHide(_) | Directive::AddConditions(_) => (),
+                Directive::Ignore(Matcher::Section(section)) => {
+                    if resolver.section(&section, rank) {
+                        builder.add_section_literal_action(section, SectionAction::Ignore);
+                    }
                 }
-                source = Some(Source::AutoEnv);
+                Directive::Ignore(Matcher::SectionRegex(section)) => {
+                    resolver.section_regex(&section);
+                    builder.add_section_regex_action(section, SectionAction::Ignore);
+                }
+                Directive::Ignore(Matcher::Except(matcher, exceptions)) => {
+                    resolver.except(&matcher, &exceptions, (Action::Ignore, rule), rank)?;
+                }
+                Directive::Ignore(matcher) => {
+                    add_merge_action(
+                        &mut builder,
+                        &mut resolver,
+                        "ignore",
+                        rank,
+                        matcher,
+                        Action::Ignore,
+                    )?;
+                }
+                Directive::Transform(matcher, transform, args) => {
+                    let t = cache::transform(&transform, &args)?;
+                    add_merge_action(
+                        &mut builder,
+                        &mut resolver,
+                        "transform",
+                        rank,
+                        matcher,
+                        Action::Transform(t),
+                    )?;
+                }
+                Directive::Set {
+                    section,
+                    key,
+                    value,
+                    separator,
+                } => {
+                    // Set is a transform under the hood, but needs special support
+                    // to enable adding lines that don't exist. This is handled inside
+                    // the mutations builder.
+                    if !resolver.literal(&section, &key, rank) {
+                        continue;
+                    }
+                    builder.add_setter(
+                        section,
+                        key,
+                        &value,
+                        &separator.unwrap_or_else(|| " = ".to_string()),
+                    );
+                }
+                // A default is a setter until the key exists, after that it is
+                // an ignore.
+                Directive::Default {
+                    section,
+                    key,
+                    value,
+                    separator,
+                } => {
+                    if !resolver.literal(&section, &key, rank) {
+                        continue;
+                    }
+                    if system.has_key(&section, &key) {
+                        builder.add_literal_action(section, &key, Action::Ignore);
+                    } else {
+                        builder.add_setter(
+                            section,
+                            key,
+                            &value,
+                            &separator.unwrap_or_else(|| " = ".to_string()),
+                        );
+                    }
+                }
+                Directive::Remove(Matcher::Section(section)) => {
+                    if resolver.section(&section, rank) {
+                        builder.add_section_literal_action(section, SectionAction::Delete);
+                    }
+                }
+                Directive::Remove(Matcher::SectionRegex(section)) => {
+                    resolver.section_regex(&section);
+                    builder.add_section_regex_action(section, SectionAction::Delete);
+                }
+                Directive::Remove(matcher) => {
+                    add_merge_action(
+                        &mut builder,
+                        &mut resolver,
+                        "remove",
+                        rank,
+                        matcher,
+                        Action::Delete,
+                    )?;
+                }
+                Directive::NoWarnMultipleKeyMatches => resolver.no_warn(),
             }
-            Directive::SourceAutoPath => {
-                if source.is_some() {
-                    return Err(anyhow!("Duplicate source directives not allowed!"));
+        }
+        // Finding the unknown entries tests every key of the target file
+        // against all rules (compiling a regex set for them). Removing is
+        // what happens anyway, so this is skipped unless a policy asks for
+        // something else. Otherwise it costs about 5 ms for 300 regex rules
+        // and a file with 2000 keys.
+        let is_remove = |policy: &Option<(UnknownPolicy, Origin)>| {
+            policy
+                .as_ref()
+                .is_none_or(|(policy, _)| *policy == UnknownPolicy::Remove)
+        };
+        if !is_remove(&unknown_keys) || !is_remove(&unknown_sections) {
+            add_unknown(
+                &mut builder,
+                resolver.unknown(&system, &source),
+                unknown_keys,
+                unknown_sections,
+            );
+        }
+        for (section, key, (action, rule)) in resolver.finish()? {
+            builder.select(rule);
+            builder.add_literal_action(section, &key, action);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Apply the `unknown-keys` and `unknown-sections` policies, given with the
+/// location of the directive that set them.
+fn add_unknown(
+    builder: &mut TracingBuilder,
+    unknown: Unknown,
+    unknown_keys: Option<(UnknownPolicy, Origin)>,
+    unknown_sections: Option<(UnknownPolicy, Origin)>,
+) {
+    let (unknown_keys, keys_rule) = unknown_rule(builder, unknown_keys, Directive::UnknownKeys);
+    let (unknown_sections, sections_rule) =
+        unknown_rule(builder, unknown_sections, Directive::UnknownSections);
+    builder.select(keys_rule);
+    for (section, key) in unknown.keys {
+        match unknown_keys {
+            UnknownPolicy::Keep => {
+                builder.add_literal_action(section, &key, Action::Ignore);
+            }
+            UnknownPolicy::Remove => (),
+            UnknownPolicy::Warn => {
+                log::warn!("Removing {section}/{key}, it is not in the source file (unknown-keys)");
+            }
+        }
+    }
+    builder.select(sections_rule);
+    for section in unknown.sections {
+        match unknown_sections {
+            // Keep comments and empty sections too, unless other rules need
+            // to apply to some keys
+            UnknownPolicy::Keep if section.untouched => {
+                builder.add_section_literal_action(section.name, SectionAction::Ignore);
+            }
+            UnknownPolicy::Keep => {
+                for key in section.keys {
+                    builder.add_literal_action(section.name.clone(), &key, Action::Ignore);
                 }
-                source = Some(Source::AutoPath);
             }
-            // Not relevant for filtering
-            Directive::Set { .. } => (),
-            Directive::Transform(_, _, _) => (),
-            Directive::Remove(_) => (),
-            Directive::NoWarnMultipleKeyMatches => {
-                builder.warn_on_multiple_matches(false);
+            UnknownPolicy::Remove => (),
+            UnknownPolicy::Warn if section.untouched => {
+                log::warn!(
+                    "Removing section {}, it is not in the source file (unknown-sections)",
+                    section.name
+                );
+            }
+            UnknownPolicy::Warn => {
+                for key in section.keys {
+                    log::warn!(
+                        "Removing {}/{key}, its section is not in the source file \
+                         (unknown-sections)",
+                        section.name
+                    );
+                }
             }
         }
     }
+}
 
+/// Start the rule for an unknown policy, defaulting to removing
+fn unknown_rule(
+    builder: &mut TracingBuilder,
+    policy: Option<(UnknownPolicy, Origin)>,
+    directive: fn(UnknownPolicy) -> Directive,
+) -> (UnknownPolicy, usize) {
+    let (policy, origin) = policy.unwrap_or_else(|| (UnknownPolicy::Remove, Origin::default()));
+    (policy, builder.rule(origin, &directive(policy)))
+}
+
+/// Parse directives for operation
+pub(crate) fn parse_for_add(
+    src: &str,
+    script_path: &Utf8Path,
+    include_root: &Utf8Path,
+) -> Result<Config<FilterRules>, anyhow::Error> {
+    let result = load_directives(src, script_path, include_root)?;
+    let add_conditions = result
+        .iter()
+        .rev()
+        .find_map(|(_, directive)| match directive {
+            Directive::AddConditions(mode) => Some(*mode),
+            _ => None,
+        })
+        .unwrap_or(AddConditions::Skip);
+    let result = conditions::resolve(result, Mode::Add(add_conditions))?;
+    let (common, directives) = extract_common(result, None)?;
     Ok(Config {
-        source: source.ok_or_else(|| anyhow!("No source directive found"))?,
-        mutations: builder.build()?,
+        source: common.source,
+        mutations: FilterRules { directives },
+        renames: common.renames,
+        encoding: common.encoding,
+        line_endings: common.line_endings,
     })
 }
 
-fn add_merge_action(builder: &mut MutationsBuilder, matcher: Matcher, action: Action) {
+impl FilterRules {
+    /// Build the filter actions for the given file contents (with the renames
+    /// already applied).
+    pub(crate) fn build(self, contents: &str) -> anyhow::Result<FilterActions> {
+        let contents = IniState::new(contents);
+        let states = [&contents];
+        let (rules, policy) = priority::order(self.directives);
+        let mut resolver = Resolver::new(&states, policy);
+        // Adding is not on the fast path
+        resolver.report_overlaps();
+        let mut builder = FilterActionsBuilder::new();
+        // Overlapping regexes are reported by the resolver instead
+        builder.warn_on_multiple_matches(false);
+
+        for Rule {
+            rank, directive, ..
+        } in rules
+        {
+            match directive {
+                // Includes are already expanded by load_directives()
+                Directive::WS | Directive::Include(_) => (),
+                // Conditionals are already resolved
+                Directive::If(_) | Directive::Else | Directive::EndIf => (),
+                // Handled by parse_for_add() and extract_common()
+                Directive::AddConditions(_)
+                | Directive::Source(_)
+                | Directive::SourceAutoEnv
+                | Directive::SourceAutoPath
+                | Directive::RenameSection { .. }
+                | Directive::RenameKey { .. }
+                | Directive::Encoding(_)
+                | Directive::LineEndings(_) => (),
+                // Handled by priority::order()
+                Directive::Priority(_, _) | Directive::MatchPolicy(_) => (),
+                Directive::AddHide(matcher) => {
+                    add_filter_action(
+                        &mut builder,
+                        &mut resolver,
+                        "add:hide",
+                        rank,
+                        matcher,
+                        FilterAction::Replace("HIDDEN"),
+                    )?;
+                }
+                Directive::Ignore(Matcher::Except(matcher, exceptions)) => {
+                    resolver.except(&matcher, &exceptions, FilterAction::Remove, rank)?;
+                }
+                Directive::AddRemove(matcher) => {
+                    add_filter_action(
+                        &mut builder,
+                        &mut resolver,
+                        "add:remove",
+                        rank,
+                        matcher,
+                        FilterAction::Remove,
+                    )?;
+                }
+                Directive::Ignore(matcher) => {
+                    add_filter_action(
+                        &mut builder,
+                        &mut resolver,
+                        "ignore",
+                        rank,
+                        matcher,
+                        FilterAction::Remove,
+                    )?;
+                }
+                // The value is owned by the system once it exists, like ignore
+                Directive::Default { section, key, .. } => {
+                    if resolver.literal(&section, &key, rank) {
+                        builder.add_literal_action(section, &key, FilterAction::Remove);
+                    }
+                }
+                // Not relevant for filtering
+                Directive::Set { .. } => (),
+                Directive::Transform(_, _, _) => (),
+                Directive::Remove(_) => (),
+                Directive::Strict(_)
+                | Directive::ConflictPolicy(_)
+                | Directive::OnError(_)
+                | Directive::Validate(_) => (),
+                Directive::UnknownKeys(_) | Directive::UnknownSections(_) => (),
+                Directive::NoWarnMultipleKeyMatches => resolver.no_warn(),
+            }
+        }
+        for (section, key, action) in resolver.finish()? {
+            builder.add_literal_action(section, &key, action);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Add a section+key action for merging.
+///
+/// Whole section matches have to be handled by the caller, as only some
+/// directives support them. Value matchers are resolved into literal matches.
+/// The `verb` (e.g. "ignore") is used to describe the rule in diagnostics.
+fn add_merge_action(
+    builder: &mut TracingBuilder,
+    resolver: &mut Resolver<'_, (Action, usize)>,
+    verb: &str,
+    rank: Rank,
+    matcher: Matcher,
+    action: Action,
+) -> anyhow::Result<()> {
+    let description = format!("{verb} {matcher}");
     match matcher {
-        Matcher::Section(_) => panic!("Section match not valid in add_merge_action()"),
-        Matcher::SectionRegex(_) => panic!("SectionRegex match not valid in add_merge_action()"),
+        Matcher::Section(section) => Err(anyhow!(
+            "Matching an entire section (\"{section}\") is not supported here"
+        )),
+        Matcher::SectionRegex(section) => Err(anyhow!(
+            "Matching entire sections by regex (\"{section}\") is not supported here"
+        )),
         Matcher::Literal(section, key) => {
-            builder.add_literal_action(section, &key, action);
+            if resolver.literal(&section, &key, rank) {
+                builder.add_literal_action(section, &key, action);
+            }
+            Ok(())
         }
         Matcher::Regex(section, key) => {
+            resolver.regex(&section, &key, description, rank);
             builder.add_regex_action(&section, &key, action);
+            Ok(())
+        }
+        Matcher::Value(matcher, value) => {
+            for (section, key) in resolver.value(&matcher, &value, rank)? {
+                builder.add_literal_action(section, &key, action.clone());
+            }
+            Ok(())
         }
+        Matcher::Except(_, _) => Err(anyhow!("except is only supported for ignore")),
     }
 }
 
-fn add_filter_action(builder: &mut FilterActionsBuilder, matcher: Matcher, action: FilterAction) {
+/// Add an action for filtering, see [`add_merge_action`].
+fn add_filter_action(
+    builder: &mut FilterActionsBuilder,
+    resolver: &mut Resolver<'_, FilterAction>,
+    verb: &str,
+    rank: Rank,
+    matcher: Matcher,
+    action: FilterAction,
+) -> anyhow::Result<()> {
+    let description = format!("{verb} {matcher}");
     match matcher {
         Matcher::Section(section) => {
-            builder.add_section_literal_action(section, action);
+            if resolver.section(&section, rank) {
+                builder.add_section_literal_action(section, action);
+            }
         }
         Matcher::SectionRegex(section) => {
+            resolver.section_regex(&section);
             builder.add_section_regex_action(section, action);
         }
         Matcher::Literal(section, key) => {
-            builder.add_literal_action(section, &key, action);
+            if resolver.literal(&section, &key, rank) {
+                builder.add_literal_action(section, &key, action);
+            }
         }
         Matcher::Regex(section, key) => {
+            resolver.regex(&section, &key, description, rank);
             builder.add_regex_action(&section, &key, action);
         }
+        Matcher::Value(matcher, value) => {
+            for (section, key) in resolver.value(&matcher, &value, rank)? {
+                builder.add_literal_action(section, &key, action);
+            }
+        }
+        Matcher::Except(_, _) => return Err(anyhow!("except is only supported for ignore")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn check_section_matchers() {
+        let mut builder = TracingBuilder::new();
+        let state = IniState::default();
+        let states = [&state];
+        let mut resolver = Resolver::new(&states, None);
+        let rank = Rank::new(0, 0);
+        let err = add_merge_action(
+            &mut builder,
+            &mut resolver,
+            "ignore",
+            rank,
+            Matcher::Section("a".into()),
+            Action::Ignore,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Matching an entire section (\"a\") is not supported here"
+        );
+        let err = add_merge_action(
+            &mut builder,
+            &mut resolver,
+            "remove",
+            rank,
+            Matcher::SectionRegex("a.*".into()),
+            Action::Delete,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Matching entire sections by regex (\"a.*\") is not supported here"
+        );
+    }
+
+    #[test]
+    fn check_include() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        std::fs::create_dir(root.join("shared")).unwrap();
+        std::fs::write(
+            root.join("shared/a.rules"),
+            "ignore \"a\" \"b\"\ninclude \"shared/b.rules\"\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("shared/b.rules"), "ignore section \"c\"\n").unwrap();
+
+        let script = root.join("modify_test");
+        let directives =
+            load_directives("source auto\ninclude \"shared/a.rules\"\n", &script, &root).unwrap();
+        let directives: Vec<_> = directives
+            .into_iter()
+            .filter(|(_, v)| *v != Directive::WS)
+            .map(|(origin, v)| (origin.file.file_name().unwrap().to_owned(), origin.line, v))
+            .collect();
+        assert_eq!(
+            directives,
+            vec![
+                ("modify_test".into(), 1, Directive::SourceAutoEnv),
+                (
+                    "a.rules".into(),
+                    1,
+                    Directive::Ignore(Matcher::Literal("a".into(), "b".into()))
+                ),
+                (
+                    "b.rules".into(),
+                    1,
+                    Directive::Ignore(Matcher::Section("c".into()))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_conditional_errors() {
+        let script = Utf8Path::new("modify_test");
+        let root = Utf8Path::new(".");
+        let err = load_directives("}", script, root).unwrap_err();
+        assert_eq!(err.to_string(), "modify_test:1: Unmatched closing brace");
+        let err = load_directives("source auto\n} else {\n}", script, root).unwrap_err();
+        assert_eq!(err.to_string(), "modify_test:2: else without matching if");
+        let err =
+            load_directives("if os \"a\" {\n} else {\n} else {\n}", script, root).unwrap_err();
+        assert_eq!(err.to_string(), "modify_test:3: Multiple else in if block");
+        let err = load_directives("if os \"a\" {\nif os \"b\" {\n}", script, root).unwrap_err();
+        assert_eq!(err.to_string(), "modify_test:1: Unterminated if block");
+    }
+
+    #[test]
+    fn check_directive_errors() {
+        let script = Utf8Path::new("modify_test");
+        let root = Utf8Path::new(".");
+        let err = |src| load_directives(src, script, root).unwrap_err().to_string();
+        assert!(
+            err("source auto\nignore section regex \"[\"")
+                .starts_with("modify_test:2: Invalid section regex \"[\": regex parse error:")
+        );
+        assert!(
+            err("ignore regex \"a\" \"b\" except regex \"(\"")
+                .starts_with("modify_test:1: Invalid except regex \"(\": regex parse error:")
+        );
+        assert_eq!(
+            err("\n\ntransform \"a\" \"b\" nope priority=1"),
+            "modify_test:3: Invalid transform specified: nope: Matching variant not found"
+        );
+        // Inside conditional blocks too
+        assert!(
+            err("if os \"nope\" {\n  ignore regex \"a\" \"(\"\n}")
+                .starts_with("modify_test:2: Invalid regex \"a\" \"(\"")
+        );
+    }
+
+    #[test]
+    fn check_strict() {
+        let script = Utf8Path::new("modify_test");
+        let root = Utf8Path::new(".");
+        let system = "[a]\nb=1\n";
+        let check = |src, force| {
+            parse_for_merge(src, script, root)
+                .unwrap()
+                .mutations
+                .check_unmatched(force, script, system, "")
+        };
+        // Matches, or not strict
+        check("source auto\nstrict\nignore \"a\" \"b\"", false).unwrap();
+        check("source auto\nignore \"a\" \"c\"", false).unwrap();
+        check("source auto\nstrict warn\nignore \"a\" \"c\"", false).unwrap();
+        let err = check("source auto\nstrict warn\nignore \"a\" \"c\"", true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "modify_test: Rules that match nothing in the target or source file:\n  \
+             ignore \"a\" \"c\""
+        );
+        check("source auto\nstrict\nremove regex \".\" \"x\"", false).unwrap_err();
+    }
+
+    #[test]
+    fn check_include_errors() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        std::fs::write(root.join("a.rules"), "include \"b.rules\"\n").unwrap();
+        std::fs::write(root.join("b.rules"), "\ninclude \"a.rules\"\n").unwrap();
+
+        let script = root.join("modify_test");
+        let err = load_directives("source auto\ninclude \"missing\"", &script, &root).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with(&format!("{script}:2: Failed to include \"missing\""))
+        );
+
+        let err = load_directives("include \"a.rules\"", &script, &root).unwrap_err();
+        let root = root.canonicalize_utf8().unwrap();
+        assert_eq!(
+            format!("{:#}", err.root_cause()),
+            format!(
+                "{}:2: Include cycle detected: {} -> {} -> {}",
+                root.join("b.rules"),
+                root.join("a.rules"),
+                root.join("b.rules"),
+                root.join("a.rules"),
+            )
+        );
     }
 }
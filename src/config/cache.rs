@@ -0,0 +1,86 @@
+//! Memoization of compiled regexes and constructed transforms.
+//!
+//! These only depend on the rules, not on the files being merged. When many
+//! files are processed in one process (`--process-batch`) they are shared
+//! between the files instead of being built again for each file. This doesn't
+//! cover the mutations built by ini-merge, which compile their own regex set.
+use crate::transforms::Transform;
+use anyhow::anyhow;
+use ini_merge::mutations::transforms::TransformerDispatch;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+
+/// A transform: its name and arguments
+type TransformKey = (String, BTreeMap<String, String>);
+
+static REGEXES: LazyLock<Mutex<HashMap<String, Regex>>> = LazyLock::new(Default::default);
+static TRANSFORMS: LazyLock<Mutex<HashMap<TransformKey, TransformerDispatch>>> =
+    LazyLock::new(Default::default);
+
+/// Look up `key`, or build the value without holding the lock (so that other
+/// threads aren't blocked meanwhile) and remember it.
+fn get_or_build<K, V, E>(
+    cache: &Mutex<HashMap<K, V>>,
+    key: K,
+    build: impl FnOnce() -> Result<V, E>,
+) -> Result<V, E>
+where
+    K: Eq + Hash,
+    V: Clone,
+{
+    let lock = || cache.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(value) = lock().get(&key) {
+        return Ok(value.clone());
+    }
+    let value = build()?;
+    lock().insert(key, value.clone());
+    Ok(value)
+}
+
+/// Compile a regex
+pub(super) fn regex(pattern: &str) -> Result<Regex, regex::Error> {
+    get_or_build(&REGEXES, pattern.to_owned(), || Regex::new(pattern))
+}
+
+/// Create a transformer based on name
+pub(super) fn transform(
+    transform: &str,
+    args: &HashMap<String, String>,
+) -> anyhow::Result<TransformerDispatch> {
+    let key = (
+        transform.to_owned(),
+        args.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+    );
+    get_or_build(&TRANSFORMS, key, || {
+        Transform::from_str(transform)
+            .map_err(|err| anyhow!("Invalid transform specified: {transform}: {err}"))?
+            .construct(args)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_regex() {
+        let re = regex("^a.c$").unwrap();
+        assert!(re.is_match("abc"));
+        assert!(regex("^a.c$").unwrap().is_match("axc"));
+        assert!(regex("(").is_err());
+    }
+
+    #[test]
+    fn check_transform() {
+        let args = HashMap::from([("separator".to_owned(), ",".to_owned())]);
+        assert!(transform("unsorted-list", &args).is_ok());
+        assert!(transform("unsorted-list", &args).is_ok());
+        assert!(transform("no-such-transform", &args).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! Evaluation of conditional (`if`) blocks.
+use super::parser::AddConditions;
+use super::parser::Condition;
+use super::parser::Directive;
+use super::parser::EnvTest;
+use anyhow::Context;
+use anyhow::anyhow;
+use duct::cmd;
+
+/// How conditional blocks should be resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Mode {
+    /// Evaluate the conditions on the current machine
+    Evaluate,
+    /// Handle conditional blocks as configured for adding
+    Add(AddConditions),
+}
+
+/// State of one (possibly nested) conditional block
+#[derive(Debug)]
+struct Block {
+    /// Whether the block containing this block is active
+    parent_active: bool,
+    /// Whether the condition was true (only valid when evaluating)
+    condition: bool,
+    /// Whether we are in the else branch
+    in_else: bool,
+}
+
+impl Block {
+    fn active(&self, mode: Mode) -> bool {
+        self.parent_active
+            && match mode {
+                Mode::Evaluate => self.condition != self.in_else,
+                Mode::Add(AddConditions::Skip) => false,
+                Mode::Add(AddConditions::AllBranches) => true,
+            }
+    }
+}
+
+/// Remove conditional blocks, keeping only the directives in active branches.
+///
+/// The blocks are assumed to be balanced (this is checked when loading).
+/// Each directive can carry extra data (such as where it was written) that is
+/// passed through unchanged.
+pub(super) fn resolve<T>(
+    directives: Vec<(T, Directive)>,
+    mode: Mode,
+) -> anyhow::Result<Vec<(T, Directive)>> {
+    let mut hostname = None;
+    let mut blocks: Vec<Block> = vec![];
+    let mut result = vec![];
+    for (data, directive) in directives {
+        let active = blocks.last().is_none_or(|block| block.active(mode));
+        match directive {
+            Directive::If(condition) => {
+                // Only evaluate what is needed, e.g. to not look up the
+                // hostname unless required.
+                let condition = mode == Mode::Evaluate && active && {
+                    evaluate(&condition, &mut hostname)
+                        .with_context(|| format!("Failed to evaluate condition {condition:?}"))?
+                };
+                blocks.push(Block {
+                    parent_active: active,
+                    condition,
+                    in_else: false,
+                });
+            }
+            Directive::Else => {
+                let block = blocks
+                    .last_mut()
+                    .ok_or_else(|| anyhow!("else without matching if"))?;
+                block.in_else = true;
+            }
+            Directive::EndIf => {
+                blocks
+                    .pop()
+                    .ok_or_else(|| anyhow!("Unmatched closing brace"))?;
+            }
+            directive if active => result.push((data, directive)),
+            _ => (),
+        }
+    }
+    Ok(result)
+}
+
+/// Evaluate a single condition
+///
+/// The hostname is looked up on first use and cached in `hostname`.
+fn evaluate(condition: &Condition, hostname: &mut Option<String>) -> anyhow::Result<bool> {
+    Ok(match condition {
+        Condition::Hostname(expected) => {
+            if hostname.is_none() {
+                *hostname = Some(short_hostname()?);
+            }
+            hostname.as_ref() == Some(expected)
+        }
+        Condition::Os(expected) => std::env::consts::OS == expected,
+        Condition::Env(var, test) => {
+            let value = std::env::var(var).ok();
+            match test {
+                EnvTest::IsSet => value.is_some(),
+                EnvTest::Equals(expected) => value.as_ref() == Some(expected),
+                EnvTest::NotEquals(expected) => value.as_ref() != Some(expected),
+            }
+        }
+        Condition::Exists(path) => std::fs::exists(path)?,
+    })
+}
+
+/// Get the hostname up to the first dot, like chezmoi's `.chezmoi.hostname`.
+///
+/// chezmoi exports this to scripts as `CHEZMOI_HOSTNAME`, fall back to
+/// asking the OS when not running under chezmoi.
+fn short_hostname() -> anyhow::Result<String> {
+    let hostname = match std::env::var("CHEZMOI_HOSTNAME") {
+        Ok(hostname) => hostname,
+        Err(_) => {
+            let output = cmd!("hostname")
+                .stdout_capture()
+                .stderr_null()
+                .unchecked()
+                .run()
+                .context("Failed to run hostname")?;
+            if !output.status.success() {
+                return Err(anyhow!("hostname failed with error code {}", output.status));
+            }
+            String::from_utf8(output.stdout)?
+        }
+    };
+    let hostname = hostname.trim();
+    Ok(hostname
+        .split_once('.')
+        .map_or(hostname, |(short, _)| short)
+        .to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parser::Matcher;
+    use pretty_assertions::assert_eq;
+
+    fn ignore(section: &str) -> Directive {
+        Directive::Ignore(Matcher::Section(section.into()))
+    }
+
+    fn run(directives: Vec<Directive>, mode: Mode) -> Vec<Directive> {
+        let directives = directives.into_iter().map(|d| ((), d)).collect();
+        resolve(directives, mode)
+            .unwrap()
+            .into_iter()
+            .map(|((), d)| d)
+            .collect()
+    }
+
+    fn example() -> Vec<Directive> {
+        let this_os = Condition::Os(std::env::consts::OS.into());
+        let other_os = Condition::Os("no-such-os".into());
+        vec![
+            ignore("a"),
+            Directive::If(this_os),
+            ignore("b"),
+            Directive::If(other_os),
+            ignore("c"),
+            Directive::Else,
+            ignore("d"),
+            Directive::EndIf,
+            Directive::Else,
+            ignore("e"),
+            Directive::EndIf,
+            ignore("f"),
+        ]
+    }
+
+    #[test]
+    fn check_evaluate() {
+        let out = run(example(), Mode::Evaluate);
+        assert_eq!(
+            out,
+            vec![ignore("a"), ignore("b"), ignore("d"), ignore("f")]
+        );
+    }
+
+    #[test]
+    fn check_add_modes() {
+        let out = run(example(), Mode::Add(AddConditions::Skip));
+        assert_eq!(out, vec![ignore("a"), ignore("f")]);
+        let out = run(example(), Mode::Add(AddConditions::AllBranches));
+        assert_eq!(
+            out,
+            vec![
+                ignore("a"),
+                ignore("b"),
+                ignore("c"),
+                ignore("d"),
+                ignore("e"),
+                ignore("f")
+            ]
+        );
+    }
+
+    #[test]
+    fn check_env_conditions() {
+        let mut hostname = None;
+        let set = |test| Condition::Env("CARGO_MANIFEST_DIR".into(), test);
+        let manifest_dir = env!("CARGO_MANIFEST_DIR").to_string();
+        assert!(evaluate(&set(EnvTest::IsSet), &mut hostname).unwrap());
+        assert!(evaluate(&set(EnvTest::Equals(manifest_dir.clone())), &mut hostname).unwrap());
+        assert!(!evaluate(&set(EnvTest::NotEquals(manifest_dir)), &mut hostname).unwrap());
+        let unset = Condition::Env("CHMM_NO_SUCH_VARIABLE".into(), EnvTest::IsSet);
+        assert!(!evaluate(&unset, &mut hostname).unwrap());
+    }
+}
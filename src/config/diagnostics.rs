@@ -0,0 +1,356 @@
+//! Human readable diagnostics for errors in config files.
+use super::parser::KEYWORDS;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt::Display;
+use winnow::error::ContextError;
+use winnow::error::ParseError;
+use winnow::error::StrContext;
+use winnow::error::StrContextValue;
+
+/// A parse error in a config file, rendered in the style of rustc.
+///
+/// ```text
+/// error: expected quoted string
+///  --> modify_kdeglobals:3:16
+///   |
+/// 3 | ignore section General
+///   |                ^ expected quoted string
+///   |
+///   = note: while parsing `ignore`
+/// ```
+#[derive(Debug)]
+pub(super) struct ParseDiagnostic {
+    file: Utf8PathBuf,
+    line: usize,
+    column: usize,
+    line_text: String,
+    message: String,
+    note: Option<String>,
+    help: Option<String>,
+}
+
+impl ParseDiagnostic {
+    /// Create a diagnostic from a winnow parse error
+    pub(super) fn new(file: &Utf8Path, src: &str, error: &ParseError<&str, ContextError>) -> Self {
+        let offset = error.offset();
+        let (line, column) = line_column(src, offset);
+        let line_start = src[..offset].rfind(['\n', '\r']).map_or(0, |idx| idx + 1);
+        let line_text = src[line_start..]
+            .split(['\n', '\r'])
+            .next()
+            .unwrap_or_default();
+        let word: String = src[offset..]
+            .chars()
+            .take_while(|c| !c.is_whitespace() && *c != '"')
+            .collect();
+
+        let inner = error.inner();
+        let mut expected: Vec<&StrContextValue> = vec![];
+        for ctx in inner.context() {
+            if let StrContext::Expected(value) = ctx
+                && !expected.contains(&value)
+            {
+                expected.push(value);
+            }
+        }
+        let label = inner.context().find_map(|ctx| match ctx {
+            StrContext::Label(label) => Some(*label),
+            _ => None,
+        });
+
+        let escape_error = expected.iter().any(|value| {
+            matches!(value, StrContextValue::Description(d) if d.starts_with("escape sequence"))
+        });
+
+        let (message, candidates): (String, Vec<&str>) = if !expected.is_empty() {
+            let literals = expected
+                .iter()
+                .filter_map(|value| match value {
+                    StrContextValue::StringLiteral(s) => Some(*s),
+                    _ => None,
+                })
+                .collect();
+            (
+                format!("expected {}", format_alternatives(&expected)),
+                literals,
+            )
+        } else if line_text[..offset - line_start].trim().is_empty() {
+            (format!("unknown directive `{word}`"), KEYWORDS.to_vec())
+        } else {
+            ("expected end of line".to_string(), vec![])
+        };
+
+        Self {
+            file: file.to_owned(),
+            line,
+            column,
+            line_text: line_text.to_owned(),
+            message,
+            note: label.map(|label| format!("while parsing `{label}`")),
+            help: suggest(&word, &candidates)
+                .map(|s| format!("did you mean `{s}`?"))
+                .or_else(|| {
+                    escape_error.then(|| {
+                        "use a raw string (r\"...\" or '...') to avoid escaping, e.g. in regexes"
+                            .to_string()
+                    })
+                }),
+        }
+    }
+}
+
+impl Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        // Keep tabs in the padding so the caret lines up with the text above
+        let padding: String = self
+            .line_text
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(f, "{gutter}--> {}:{}:{}", self.file, self.line, self.column)?;
+        writeln!(f, "{gutter} |")?;
+        writeln!(f, "{} | {}", self.line, self.line_text)?;
+        write!(f, "{gutter} | {padding}^ {}", self.message)?;
+        if self.note.is_some() || self.help.is_some() {
+            write!(f, "\n{gutter} |")?;
+        }
+        if let Some(ref note) = self.note {
+            write!(f, "\n{gutter} = note: {note}")?;
+        }
+        if let Some(ref help) = self.help {
+            write!(f, "\n{gutter} = help: {help}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseDiagnostic {}
+
+/// Where a directive was written, used to explain which rule decided what
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Origin {
+    pub(crate) file: Utf8PathBuf,
+    /// 1-based line number
+    pub(crate) line: usize,
+    /// The text of the line, without surrounding whitespace
+    pub(crate) text: String,
+}
+
+impl Origin {
+    pub(super) fn new(file: &Utf8Path, src: &str, offset: usize) -> Self {
+        let (line, _) = line_column(src, offset);
+        let line_start = src[..offset].rfind(['\n', '\r']).map_or(0, |idx| idx + 1);
+        let text = src[line_start..]
+            .split(['\n', '\r'])
+            .next()
+            .unwrap_or_default();
+        Self {
+            file: file.to_owned(),
+            line,
+            text: text.trim().to_owned(),
+        }
+    }
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.text)
+    }
+}
+
+/// Compute the (1-based) line and column (in characters) of a byte offset
+pub(super) fn line_column(src: &str, offset: usize) -> (usize, usize) {
+    let prefix = &src[..offset];
+    let line = prefix.matches('\n').count() + prefix.matches('\r').count()
+        - prefix.matches("\r\n").count()
+        + 1;
+    let line_start = prefix.rfind(['\n', '\r']).map_or(0, |idx| idx + 1);
+    (line, prefix[line_start..].chars().count() + 1)
+}
+
+/// Format a list of alternatives as "a, b or c"
+fn format_alternatives(values: &[&StrContextValue]) -> String {
+    match values {
+        [] => String::new(),
+        [single] => single.to_string(),
+        [init @ .., last] => {
+            let init: Vec<String> = init.iter().map(ToString::to_string).collect();
+            format!("{} or {last}", init.join(", "))
+        }
+    }
+}
+
+/// Find the candidate closest to `word`, if any is close enough to be a
+/// plausible typo.
+fn suggest<'a>(word: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    if word.is_empty() {
+        return None;
+    }
+    let max_distance = (word.chars().count() / 3).max(1);
+    candidates
+        .iter()
+        .map(|candidate| (levenshtein(word, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Edit distance between two strings
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != *cb);
+            cur.push(substitution.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parser::parse_config;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use winnow::Parser;
+
+    fn diagnose(src: &str) -> String {
+        let err = parse_config.parse(src).unwrap_err();
+        ParseDiagnostic::new(Utf8Path::new("modify_test"), src, &err).to_string()
+    }
+
+    #[test]
+    fn check_line_column() {
+        let src = "a\nb\r\nc\rdef";
+        assert_eq!(line_column(src, 0), (1, 1));
+        assert_eq!(line_column(src, 2), (2, 1));
+        assert_eq!(line_column(src, 5), (3, 1));
+        assert_eq!(line_column(src, 9), (4, 3));
+    }
+
+    #[test]
+    fn check_levenshtein() {
+        assert_eq!(levenshtein("transfrom", "transform"), 2);
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(levenshtein("ignore", "ignore"), 0);
+    }
+
+    #[test]
+    fn check_unknown_directive() {
+        let out = diagnose("source auto\n\ntransfrom \"a\" \"b\" kde-shortcut\n");
+        assert_eq!(
+            out,
+            indoc! {r#"
+            error: unknown directive `transfrom`
+             --> modify_test:3:1
+              |
+            3 | transfrom "a" "b" kde-shortcut
+              | ^ unknown directive `transfrom`
+              |
+              = help: did you mean `transform`?"#}
+        );
+    }
+
+    #[test]
+    fn check_expected() {
+        let out = diagnose("source auto\nignore section General\n");
+        assert_eq!(
+            out,
+            indoc! {r#"
+            error: expected quoted string
+             --> modify_test:2:16
+              |
+            2 | ignore section General
+              |                ^ expected quoted string
+              |
+              = note: while parsing `ignore`"#}
+        );
+
+        let out = diagnose("ignore sectoin \"a\"\n");
+        assert_eq!(
+            out,
+            indoc! {r#"
+            error: expected `section`, `regex` or quoted string
+             --> modify_test:1:8
+              |
+            1 | ignore sectoin "a"
+              |        ^ expected `section`, `regex` or quoted string
+              |
+              = note: while parsing `ignore`
+              = help: did you mean `section`?"#}
+        );
+    }
+
+    #[test]
+    fn check_unterminated_string() {
+        let out = diagnose("set \"a\" \"b\" \"c\n");
+        assert_eq!(
+            out,
+            indoc! {r#"
+            error: expected `"`
+             --> modify_test:1:15
+              |
+            1 | set "a" "b" "c
+              |               ^ expected `"`
+              |
+              = note: while parsing `set`"#}
+        );
+    }
+
+    #[test]
+    fn check_escape_sequence() {
+        let out = diagnose("ignore regex \"a\" \"\\d+\"\n");
+        assert_eq!(
+            out,
+            indoc! {r#"
+            error: expected escape sequence (\\, \", \n, \t, \xNN or \u{NNNN})
+             --> modify_test:1:20
+              |
+            1 | ignore regex "a" "\d+"
+              |                    ^ expected escape sequence (\\, \", \n, \t, \xNN or \u{NNNN})
+              |
+              = note: while parsing `ignore`
+              = help: use a raw string (r"..." or '...') to avoid escaping, e.g. in regexes"#}
+        );
+    }
+
+    #[test]
+    fn check_misplaced_except() {
+        let out = diagnose("ignore \"a\" \"b\" except \"c\"\n");
+        assert_eq!(
+            out,
+            indoc! {r#"
+            error: expected end of line (except only works with ignore section and ignore regex, without value regex)
+             --> modify_test:1:16
+              |
+            1 | ignore "a" "b" except "c"
+              |                ^ expected end of line (except only works with ignore section and ignore regex, without value regex)
+              |
+              = note: while parsing `ignore`"#}
+        );
+    }
+
+    #[test]
+    fn check_trailing_garbage() {
+        let out = diagnose("ignore \"a\" \"b\" \"c\"\n");
+        assert_eq!(
+            out,
+            indoc! {r#"
+            error: expected end of line
+             --> modify_test:1:15
+              |
+            1 | ignore "a" "b" "c"
+              |               ^ expected end of line"#}
+        );
+    }
+}
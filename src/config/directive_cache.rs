@@ -0,0 +1,295 @@
+//! On-disk cache of parsed directives, to skip parsing modify scripts (and
+//! the files they include) on every run.
+//!
+//! The cache file is picked by the contents of the modify script (and the
+//! include root), not by its path: chezmoi runs modify scripts from a
+//! temporary copy with a new name every time. It stores the parsed
+//! directives (with includes expanded, but conditions not yet evaluated)
+//! together with a hash of every file they were parsed from, and is only
+//! used if all of those files are unchanged. Only hashes of the files are
+//! stored, but as the directives may contain secrets as well (in `set`
+//! directives), only the owner can read the cache.
+//!
+//! Editing a script leaves the entry for its old contents behind. Using an
+//! entry updates its modification time, and entries that haven't been used
+//! for [`MAX_UNUSED`] are deleted whenever a new entry is written.
+use super::Directives;
+use super::load_directives_tracked;
+use crate::utils;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// How long to keep entries that aren't used
+const MAX_UNUSED: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// A directory to cache parsed directives in
+#[derive(Debug)]
+pub(crate) struct DirectiveCache {
+    dir: Utf8PathBuf,
+}
+
+/// The contents of a cache file
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Version of the program that wrote the entry
+    version: String,
+    /// The path the script was parsed from, which the origins of its
+    /// directives refer to
+    script_path: Utf8PathBuf,
+    include_root: Utf8PathBuf,
+    /// Hash of the modify script
+    script: String,
+    /// Included files, with hashes of their contents
+    included: Vec<(Utf8PathBuf, String)>,
+    directives: Directives,
+}
+
+impl DirectiveCache {
+    /// The cache in `$XDG_CACHE_HOME` (or `~/.cache`) on Unix and
+    /// `%LOCALAPPDATA%` on Windows, if it can be found.
+    pub(crate) fn from_env() -> Option<Self> {
+        let base = utils::user_dir("XDG_CACHE_HOME", ".cache")?;
+        Some(Self {
+            dir: base.join("chezmoi_modify_manager").join("directives"),
+        })
+    }
+
+    /// Load the directives of a modify script from the cache if they are up
+    /// to date, otherwise parse them and update the cache.
+    ///
+    /// Problems with the cache itself are only logged, as it can always be
+    /// recreated.
+    pub(super) fn load_directives(
+        &self,
+        src: &str,
+        script_path: &Utf8Path,
+        include_root: &Utf8Path,
+    ) -> anyhow::Result<Directives> {
+        let path = self.entry_path(src, include_root);
+        if let Some(directives) = load_entry(&path, src, script_path, include_root) {
+            if let Err(err) = touch(&path) {
+                log::debug!("Failed to update modification time of {path}: {err}");
+            }
+            return Ok(directives);
+        }
+        let (directives, included) = load_directives_tracked(src, script_path, include_root)?;
+        let entry = Entry {
+            version: env!("CARGO_PKG_VERSION").into(),
+            script_path: script_path.into(),
+            include_root: include_root.into(),
+            script: utils::sha256_hex(src),
+            included: included
+                .into_iter()
+                .map(|(file, text)| (file, utils::sha256_hex(&text)))
+                .collect(),
+            directives,
+        };
+        if let Err(err) = store_entry(&path, &entry) {
+            log::debug!("Failed to update directive cache {path}: {err:#}");
+        }
+        if let Err(err) = self.evict_unused() {
+            log::debug!("Failed to clean up directive cache {}: {err}", self.dir);
+        }
+        Ok(entry.directives)
+    }
+
+    /// Delete entries that haven't been used for [`MAX_UNUSED`]
+    fn evict_unused(&self) -> std::io::Result<()> {
+        for file in std::fs::read_dir(&self.dir)? {
+            let file = file?;
+            let path = file.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let unused = file.metadata()?.modified()?.elapsed();
+            if unused.is_ok_and(|unused| unused > MAX_UNUSED) {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The cache file for a modify script with the contents `src`. The hash
+    /// only picks the file, the contents are checked when loading.
+    fn entry_path(&self, src: &str, include_root: &Utf8Path) -> Utf8PathBuf {
+        let hash = utils::sha256_hex(&format!("{include_root}\0{src}"));
+        self.dir.join(format!("{hash}.json"))
+    }
+}
+
+/// Load a cache entry, if it exists and is up to date
+fn load_entry(
+    path: &Utf8Path,
+    src: &str,
+    script_path: &Utf8Path,
+    include_root: &Utf8Path,
+) -> Option<Directives> {
+    let contents = std::fs::read(path).ok()?;
+    let entry: Entry = match serde_json::from_slice(&contents) {
+        Ok(entry) => entry,
+        Err(err) => {
+            log::debug!("Ignoring invalid directive cache {path}: {err}");
+            return None;
+        }
+    };
+    let up_to_date = entry.version == env!("CARGO_PKG_VERSION")
+        && entry.include_root == include_root
+        && entry.script == utils::sha256_hex(src)
+        && entry.included.iter().all(|(file, hash)| {
+            std::fs::read_to_string(file).is_ok_and(|current| utils::sha256_hex(&current) == *hash)
+        });
+    if !up_to_date {
+        return None;
+    }
+    // The same script may have been run from a different path
+    let mut directives = entry.directives;
+    for (origin, _) in &mut directives {
+        if origin.file == entry.script_path {
+            script_path.clone_into(&mut origin.file);
+        }
+    }
+    Some(directives)
+}
+
+/// Mark an entry as used
+fn touch(path: &Utf8Path) -> std::io::Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+fn store_entry(path: &Utf8Path, entry: &Entry) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        utils::create_private_dir(dir)?;
+    }
+    utils::write_private(path, &serde_json::to_vec(entry)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parser::Directive;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn check_cache() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        let cache = DirectiveCache {
+            dir: root.join("cache"),
+        };
+        let script = root.join("modify_test");
+        std::fs::write(root.join("shared.rules"), "ignore \"a\" \"b\"\n").unwrap();
+        let src = "source auto\ninclude \"shared.rules\"\n";
+
+        let parsed = cache.load_directives(src, &script, &root).unwrap();
+        let entry_path = cache.entry_path(src, &root);
+        assert!(entry_path.exists());
+        let cached = load_entry(&entry_path, src, &script, &root).unwrap();
+        assert_eq!(cached, parsed);
+        assert!(matches!(cached[1].1, Directive::Ignore(_)));
+        let stored: Entry = serde_json::from_slice(&std::fs::read(&entry_path).unwrap()).unwrap();
+        assert_eq!(stored.script, utils::sha256_hex(src));
+        assert_eq!(
+            stored.included[0].1,
+            utils::sha256_hex("ignore \"a\" \"b\"\n")
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&entry_path), 0o600);
+            assert_eq!(mode(&root.join("cache")), 0o700);
+        }
+
+        // Changes to the script or included files invalidate the entry
+        assert!(load_entry(&entry_path, "source auto\n", &script, &root).is_none());
+        std::fs::write(root.join("shared.rules"), "remove \"a\" \"b\"\n").unwrap();
+        assert!(load_entry(&entry_path, src, &script, &root).is_none());
+        let parsed = cache.load_directives(src, &script, &root).unwrap();
+        assert!(matches!(parsed[1].1, Directive::Remove(_)));
+        assert_eq!(
+            load_entry(&entry_path, src, &script, &root).unwrap(),
+            parsed
+        );
+
+        // A corrupt cache file is ignored and replaced
+        std::fs::write(&entry_path, "garbage").unwrap();
+        assert_eq!(cache.load_directives(src, &script, &root).unwrap(), parsed);
+        assert!(load_entry(&entry_path, src, &script, &root).is_some());
+    }
+
+    #[test]
+    fn check_cache_other_path() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        let cache = DirectiveCache {
+            dir: root.join("cache"),
+        };
+        let src = "source auto\nignore \"a\" \"b\"\n";
+        // Like chezmoi, which runs a temporary copy of the script
+        let first = root.join("tmp1/modify_test");
+        let second = root.join("tmp2/modify_test");
+        let parsed = cache.load_directives(src, &first, &root).unwrap();
+        assert_eq!(parsed[1].0.file, first);
+
+        let entry_path = cache.entry_path(src, &root);
+        let cached = load_entry(&entry_path, src, &second, &root).unwrap();
+        assert_eq!(cached[1].0.file, second);
+        assert_eq!(cache.load_directives(src, &second, &root).unwrap(), cached);
+        assert_eq!(std::fs::read_dir(root.join("cache")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn check_cache_eviction() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        let cache = DirectiveCache {
+            dir: root.join("cache"),
+        };
+        let script = root.join("modify_test");
+        let age = |path: &Utf8Path| {
+            std::fs::metadata(path)
+                .unwrap()
+                .modified()
+                .unwrap()
+                .elapsed()
+                .unwrap_or_default()
+        };
+        let set_age = |path: &Utf8Path, days: u64| {
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60))
+                .unwrap();
+        };
+        let (used, unused) = ("source auto\n", "source auto-path\n");
+        cache.load_directives(used, &script, &root).unwrap();
+        cache.load_directives(unused, &script, &root).unwrap();
+        let (used_path, unused_path) = (
+            cache.entry_path(used, &root),
+            cache.entry_path(unused, &root),
+        );
+        set_age(&used_path, 60);
+        set_age(&unused_path, 60);
+
+        // Using an entry keeps it
+        cache.load_directives(used, &script, &root).unwrap();
+        assert!(age(&used_path) < MAX_UNUSED);
+        assert!(unused_path.exists());
+
+        // Writing an entry deletes the unused ones
+        cache
+            .load_directives("source auto\nignore \"a\" \"b\"\n", &script, &root)
+            .unwrap();
+        assert!(used_path.exists());
+        assert!(!unused_path.exists());
+        assert_eq!(std::fs::read_dir(root.join("cache")).unwrap().count(), 2);
+    }
+}
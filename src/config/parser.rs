@@ -1,19 +1,38 @@
 //! Defines the winnow parser for the config file format.
+use crate::encoding::Encoding;
+use crate::encoding::LineEnding;
+use serde::Deserialize;
+use serde::Serialize;
 use std::collections::HashMap;
+use winnow::ascii::dec_int;
 use winnow::ascii::escaped;
+use winnow::ascii::space0;
 use winnow::ascii::space1;
 use winnow::combinator::alt;
+use winnow::combinator::cut_err;
 use winnow::combinator::delimited;
+use winnow::combinator::fail;
+use winnow::combinator::not;
 use winnow::combinator::opt;
+use winnow::combinator::peek;
 use winnow::combinator::preceded;
+use winnow::combinator::repeat;
 use winnow::combinator::separated;
+use winnow::combinator::terminated;
+use winnow::error::ContextError;
+use winnow::error::ErrMode;
 use winnow::error::StrContext;
+use winnow::error::StrContextValue;
 use winnow::prelude::*;
+use winnow::stream::AsChar;
+use winnow::stream::Offset;
+use winnow::token::one_of;
 use winnow::token::take_till;
 use winnow::token::take_until;
+use winnow::token::take_while;
 
 /// A directive in the config file
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub(super) enum Directive {
     /// Whitespace, ignore
     WS,
@@ -41,16 +60,151 @@ pub(super) enum Directive {
         value: String,
         separator: Option<String>,
     },
+    /// Set a key in a section to a specific value, unless it already exists
+    Default {
+        section: String,
+        key: String,
+        value: String,
+        separator: Option<String>,
+    },
     /// Remove everything matching a specific matcher
     Remove(Matcher),
     /// On add: remove everything matching a specific matcher
     AddRemove(Matcher),
     /// On add: hide the value of everything matching a specific matcher
     AddHide(Matcher),
+    /// Include directives from another file
+    Include(String),
+    /// Rename a section in the system state
+    RenameSection { from: String, to: String },
+    /// Rename a key in the system state, possibly moving it to another section
+    RenameKey {
+        section: String,
+        key: String,
+        new_section: String,
+        new_key: String,
+    },
+    /// Start of a conditional block
+    If(Condition),
+    /// Start of the else branch of a conditional block
+    Else,
+    /// End of a conditional block
+    EndIf,
+    /// On add: how to handle conditional blocks
+    AddConditions(AddConditions),
+    /// A rule (ignore, set, etc) with an explicit priority
+    Priority(i32, Box<Self>),
+    /// How to pick between multiple regexes matching the same key
+    MatchPolicy(MatchPolicy),
+    /// Report rules that don't match anything
+    Strict(Strict),
+    /// What to do with keys that only exist in the system state
+    UnknownKeys(UnknownPolicy),
+    /// What to do with sections that only exist in the system state
+    UnknownSections(UnknownPolicy),
+    /// What to do when keys were changed locally since the last apply
+    ConflictPolicy(ConflictPolicy),
+    /// What to output when merging fails
+    OnError(OnError),
+    /// The encoding of the files (`None` to detect it)
+    Encoding(Option<Encoding>),
+    /// The line endings to write (`None` to detect them)
+    LineEndings(Option<LineEnding>),
+    /// Whether to validate the merged result
+    Validate(bool),
+}
+
+/// A condition for an `if` block
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) enum Condition {
+    /// The (short) hostname matches
+    Hostname(String),
+    /// The operating system matches
+    Os(String),
+    /// Test an environment variable
+    Env(String, EnvTest),
+    /// A path exists
+    Exists(String),
+}
+
+/// The test to perform on an environment variable
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) enum EnvTest {
+    /// The variable is set (to anything)
+    IsSet,
+    /// The variable has a specific value
+    Equals(String),
+    /// The variable doesn't have a specific value
+    NotEquals(String),
+}
+
+/// How to handle conditional blocks when adding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum AddConditions {
+    /// Skip all directives inside conditional blocks
+    Skip,
+    /// Use the directives in every branch of conditional blocks
+    AllBranches,
+}
+
+/// How to pick between multiple regexes matching the same section and key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) enum MatchPolicy {
+    /// The first matching rule in the file wins
+    First,
+    /// The last matching rule in the file wins
+    Last,
+    /// The most specific matching rule wins (the one with the most literal
+    /// characters in its regexes)
+    MostSpecific,
+}
+
+/// What to do with entries that only exist in the system state (and that no
+/// rule applies to)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) enum UnknownPolicy {
+    /// Keep them, like an ignore
+    Keep,
+    /// Remove them (the default)
+    Remove,
+    /// Remove them, but log a warning
+    Warn,
+}
+
+/// How to report rules that match nothing in the files being merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Strict {
+    /// Log a warning for each rule
+    Warn,
+    /// Fail with an error listing the rules
+    Error,
+}
+
+/// What to do when the target file was changed locally since it was last
+/// written, and merging would overwrite those changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum ConflictPolicy {
+    /// Log a warning for each changed key (the default)
+    Warn,
+    /// Fail without writing anything
+    Error,
+    /// Overwrite the changes silently
+    Overwrite,
+}
+
+/// What to output when merging fails (e.g. the source file is missing)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum OnError {
+    /// Fail, making chezmoi abort (the default)
+    Fail,
+    /// Output the system state unchanged
+    Passthrough,
+    /// Output the source file unchanged
+    Source,
 }
 
 /// The different ways things can be matched.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub(super) enum Matcher {
     /// Match a whole section (exact name)
     Section(String),
@@ -60,31 +214,137 @@ pub(super) enum Matcher {
     Literal(String, String),
     /// Match section and key names using regexes
     Regex(String, String),
+    /// Match section and key (using the inner matcher), as well as the
+    /// current value using a regex
+    Value(Box<Self>, String),
+    /// Match using the inner matcher, except for the given keys
+    Except(Box<Self>, Vec<KeyPattern>),
+}
+
+/// Formats the matcher as in the config file (used in diagnostics)
+impl std::fmt::Display for Matcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Section(section) => write!(f, "section {section:?}"),
+            Self::SectionRegex(section) => write!(f, "section regex {section:?}"),
+            Self::Literal(section, key) => write!(f, "{section:?} {key:?}"),
+            Self::Regex(section, key) => write!(f, "regex {section:?} {key:?}"),
+            Self::Value(matcher, value) => write!(f, "{matcher} value regex {value:?}"),
+            Self::Except(matcher, exceptions) => {
+                write!(f, "{matcher}")?;
+                for exception in exceptions {
+                    match exception {
+                        KeyPattern::Literal(key) => write!(f, " except {key:?}")?,
+                        KeyPattern::Regex(key) => write!(f, " except regex {key:?}")?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A key name (without the section), in section blocks and `except`
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) enum KeyPattern {
+    /// Exact key name
+    Literal(String),
+    /// Key name regex
+    Regex(String),
 }
 
 /// Top level parser for the config file
-pub(super) fn parse_config(i: &mut &str) -> ModalResult<Vec<Directive>> {
+///
+/// Each directive is returned together with the byte offset it starts at.
+/// Section blocks are expanded into one directive per statement (all with the
+/// offset of the block).
+pub(super) fn parse_config(i: &mut &str) -> ModalResult<Vec<(usize, Directive)>> {
+    let start = *i;
+    let located = |i: &mut &str| -> ModalResult<Vec<(usize, Directive)>> {
+        // Allow indentation (useful inside blocks)
+        space0.parse_next(i)?;
+        let offset = i.offset_from(&start);
+        alt((
+            section_block.context(StrContext::Label("section")),
+            directive.map(|directive| vec![directive]),
+        ))
+        .parse_next(i)
+        .map(|directives| directives.into_iter().map(|d| (offset, d)).collect())
+    };
+    (separated(0.., located, newline), opt(newline))
+        .map(|(val, _): (Vec<Vec<_>>, _)| val.into_iter().flatten().collect())
+        .parse_next(i)
+}
+
+/// All directive keywords, used to suggest corrections for typos
+pub(super) const KEYWORDS: &[&str] = &[
+    "source",
+    "no-warn-multiple-key-matches",
+    "ignore",
+    "transform",
+    "set",
+    "default",
+    "remove",
+    "add:remove",
+    "add:hide",
+    "include",
+    "rename",
+    "if",
+    "add:conditions",
+    "section",
+    "match-policy",
+    "strict",
+    "unknown-keys",
+    "unknown-sections",
+    "conflict-policy",
+    "on-error",
+    "encoding",
+    "line-endings",
+    "validate",
+];
+
+/// A single directive (one line)
+fn directive(i: &mut &str) -> ModalResult<Directive> {
     let alternatives = (
         alt((
             comment.context(StrContext::Label("comment")),
             chezmoi_template.context(StrContext::Label("chezmoi template")),
             source.context(StrContext::Label("source")),
             no_warn_multiple_key_matches.context(StrContext::Label("no-warn-multiple-key-matches")),
-            ignore.context(StrContext::Label("ignore")),
-            transform.context(StrContext::Label("transform")),
+            prioritized(ignore).context(StrContext::Label("ignore")),
+            prioritized(transform).context(StrContext::Label("transform")),
+        )),
+        alt((
+            prioritized(set).context(StrContext::Label("set")),
+            prioritized(default).context(StrContext::Label("default")),
+            prioritized(remove).context(StrContext::Label("remove")),
+            prioritized(add_remove).context(StrContext::Label("add:remove")),
+            prioritized(add_hide).context(StrContext::Label("add:hide")),
+            include.context(StrContext::Label("include")),
+            rename.context(StrContext::Label("rename")),
+        )),
+        alt((
+            if_start.context(StrContext::Label("if")),
+            block_end.context(StrContext::Label("}")),
+            add_conditions.context(StrContext::Label("add:conditions")),
+        )),
+        alt((
+            match_policy.context(StrContext::Label("match-policy")),
+            strict.context(StrContext::Label("strict")),
+            unknown_keys.context(StrContext::Label("unknown-keys")),
+            unknown_sections.context(StrContext::Label("unknown-sections")),
+            conflict_policy.context(StrContext::Label("conflict-policy")),
+            on_error.context(StrContext::Label("on-error")),
+            validate.context(StrContext::Label("validate")),
         )),
         alt((
-            set.context(StrContext::Label("set")),
-            remove.context(StrContext::Label("remove")),
-            add_remove.context(StrContext::Label("add:remove")),
-            add_hide.context(StrContext::Label("add:hide")),
+            encoding.context(StrContext::Label("encoding")),
+            line_endings.context(StrContext::Label("line-endings")),
             "".map(|_| Directive::WS)
                 .context(StrContext::Label("whitespace")), // Blank lines
         )),
     );
-    (separated(0.., alt(alternatives), newline), opt(newline))
-        .map(|(val, _)| val)
-        .parse_next(i)
+    alt(alternatives).parse_next(i)
 }
 
 /// A newline (LF, CR or CRLF)
@@ -92,6 +352,27 @@ fn newline(i: &mut &str) -> ModalResult<()> {
     alt(("\r\n", "\n", "\r")).void().parse_next(i)
 }
 
+/// Whitespace separating tokens on a line
+fn whitespace(i: &mut &str) -> ModalResult<()> {
+    space1
+        .void()
+        .context(StrContext::Expected(StrContextValue::Description(
+            "whitespace",
+        )))
+        .parse_next(i)
+}
+
+/// A keyword. It must not be immediately followed by further word characters
+/// (so that e.g. `settings` isn't parsed as `set` followed by garbage).
+fn keyword<'i>(kw: &'static str) -> impl Parser<&'i str, &'i str, ErrMode<ContextError>> {
+    terminated(
+        kw,
+        not(one_of(|c: char| {
+            c.is_alphanumeric() || matches!(c, '-' | '_' | ':')
+        })),
+    )
+}
+
 /// A comment
 fn comment(i: &mut &str) -> ModalResult<Directive> {
     ('#', take_till(0.., ['\n', '\r']))
@@ -102,10 +383,14 @@ fn comment(i: &mut &str) -> ModalResult<Directive> {
 
 /// A chezmoi template. Ignored when re-adding
 fn chezmoi_template(i: &mut &str) -> ModalResult<Directive> {
-    delimited("{{", take_until(0.., "}}"), "}}")
-        .void()
-        .map(|()| Directive::WS)
-        .parse_next(i)
+    preceded(
+        "{{",
+        cut_err((take_until(0.., "}}"), "}}"))
+            .context(StrContext::Expected(StrContextValue::StringLiteral("}}"))),
+    )
+    .void()
+    .map(|()| Directive::WS)
+    .parse_next(i)
 }
 
 /// A source statement
@@ -113,85 +398,532 @@ fn source(i: &mut &str) -> ModalResult<Directive> {
     // To support working on the raw templated files before chezmoi processes
     // them we parse to the end of the line, instead of end of the quotation
     // mark.
-    (
-        "source",
-        space1,
-        alt((
-            "auto-path".map(|_| Directive::SourceAutoPath),
-            "auto".map(|_| Directive::SourceAutoEnv),
-            quoted_string_nl.map(Directive::Source),
+    preceded(
+        keyword("source"),
+        cut_err(preceded(
+            whitespace,
+            alt((
+                "auto-path".map(|_| Directive::SourceAutoPath),
+                "auto".map(|_| Directive::SourceAutoEnv),
+                quoted_string_nl.map(Directive::Source),
+                fail.context(StrContext::Expected(StrContextValue::StringLiteral("auto")))
+                    .context(StrContext::Expected(StrContextValue::Description(
+                        "quoted path",
+                    ))),
+            )),
         )),
     )
-        .map(|(_, _, result)| result)
-        .parse_next(i)
+    .parse_next(i)
 }
 
 fn no_warn_multiple_key_matches(i: &mut &str) -> ModalResult<Directive> {
-    "no-warn-multiple-key-matches"
+    keyword("no-warn-multiple-key-matches")
         .map(|_| Directive::NoWarnMultipleKeyMatches)
         .parse_next(i)
 }
 
 /// An ignore statement
 fn ignore(i: &mut &str) -> ModalResult<Directive> {
-    ("ignore", space1, matcher)
-        .map(|(_, _, pattern)| Directive::Ignore(pattern))
-        .parse_next(i)
+    preceded(
+        keyword("ignore"),
+        cut_err(preceded(whitespace, ignore_matcher)),
+    )
+    .map(Directive::Ignore)
+    .parse_next(i)
+}
+
+/// The matcher of an ignore statement, with its exceptions. `except` is only
+/// supported for whole sections and for regexes (without `value regex`).
+fn ignore_matcher(i: &mut &str) -> ModalResult<Matcher> {
+    let matcher = matcher.parse_next(i)?;
+    if matches!(
+        matcher,
+        Matcher::Section(_) | Matcher::SectionRegex(_) | Matcher::Regex(_, _)
+    ) {
+        let exceptions = exceptions.parse_next(i)?;
+        Ok(with_exceptions(matcher, exceptions))
+    } else {
+        misplaced_except.parse_next(i)?;
+        Ok(matcher)
+    }
 }
 
 fn set(i: &mut &str) -> ModalResult<Directive> {
+    preceded(keyword("set"), cut_err(set_args))
+        .map(|(section, key, value, separator)| Directive::Set {
+            section,
+            key,
+            value,
+            separator,
+        })
+        .parse_next(i)
+}
+
+fn default(i: &mut &str) -> ModalResult<Directive> {
+    preceded(keyword("default"), cut_err(set_args))
+        .map(|(section, key, value, separator)| Directive::Default {
+            section,
+            key,
+            value,
+            separator,
+        })
+        .parse_next(i)
+}
+
+/// The arguments to set and default: section, key, value and separator
+fn set_args(i: &mut &str) -> ModalResult<(String, String, String, Option<String>)> {
     (
-        "set",
-        space1,
+        whitespace,
         quoted_string,
-        space1,
+        whitespace,
         quoted_string,
-        space1,
+        whitespace,
         quoted_string,
         opt((space1, "separator=", quoted_string).map(|(_, _, v)| v)),
+    )
+        .map(|((), section, (), key, (), value, separator)| (section, key, value, separator))
+        .parse_next(i)
+}
+
+fn remove(i: &mut &str) -> ModalResult<Directive> {
+    preceded(keyword("remove"), cut_err(preceded(whitespace, matcher)))
+        .map(Directive::Remove)
+        .parse_next(i)
+}
+
+fn add_remove(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("add:remove"),
+        cut_err(preceded(whitespace, matcher)),
+    )
+    .map(Directive::AddRemove)
+    .parse_next(i)
+}
+
+fn add_hide(i: &mut &str) -> ModalResult<Directive> {
+    preceded(keyword("add:hide"), cut_err(preceded(whitespace, matcher)))
+        .map(Directive::AddHide)
+        .parse_next(i)
+}
+
+/// An include statement
+fn include(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("include"),
+        cut_err(preceded(whitespace, quoted_string)),
+    )
+    .map(Directive::Include)
+    .parse_next(i)
+}
+
+/// A rename statement
+fn rename(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("rename"),
+        cut_err(preceded(
+            whitespace,
+            alt((
+                rename_section,
+                rename_key,
+                fail.context(StrContext::Expected(StrContextValue::StringLiteral(
+                    "section",
+                )))
+                .context(StrContext::Expected(StrContextValue::Description(
+                    "quoted string",
+                ))),
+            )),
+        )),
+    )
+    .parse_next(i)
+}
+
+/// Rename of an entire section
+fn rename_section(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("section"),
+        cut_err((whitespace, quoted_string, rename_to, quoted_string)),
+    )
+    .map(|((), from, (), to)| Directive::RenameSection { from, to })
+    .parse_next(i)
+}
+
+/// Rename of a single key
+fn rename_key(i: &mut &str) -> ModalResult<Directive> {
+    (
+        quoted_string,
+        cut_err((
+            whitespace,
+            quoted_string,
+            rename_to,
+            quoted_string,
+            whitespace,
+            quoted_string,
+        )),
     )
         .map(
-            |(_, _, section, _, key, _, value, separator)| Directive::Set {
+            |(section, ((), key, (), new_section, (), new_key))| Directive::RenameKey {
                 section,
                 key,
-                value,
-                separator,
+                new_section,
+                new_key,
             },
         )
         .parse_next(i)
 }
 
-fn remove(i: &mut &str) -> ModalResult<Directive> {
-    ("remove", space1, matcher)
-        .map(|(_, _, matcher)| Directive::Remove(matcher))
+/// The `to` separating the old and new names in a rename statement
+fn rename_to(i: &mut &str) -> ModalResult<()> {
+    (
+        whitespace,
+        keyword("to").context(StrContext::Expected(StrContextValue::StringLiteral("to"))),
+        whitespace,
+    )
+        .void()
         .parse_next(i)
 }
 
-fn add_remove(i: &mut &str) -> ModalResult<Directive> {
-    ("add:remove", space1, matcher)
-        .map(|(_, _, matcher)| Directive::AddRemove(matcher))
-        .parse_next(i)
+/// Start of a conditional block
+fn if_start(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("if"),
+        cut_err(delimited(whitespace, condition, block_open)),
+    )
+    .map(Directive::If)
+    .parse_next(i)
 }
 
-fn add_hide(i: &mut &str) -> ModalResult<Directive> {
-    ("add:hide", space1, matcher)
-        .map(|(_, _, matcher)| Directive::AddHide(matcher))
+/// The condition of an `if` block
+fn condition(i: &mut &str) -> ModalResult<Condition> {
+    let env_test = opt((
+        space1,
+        alt(("==".value(true), "!=".value(false))),
+        cut_err(preceded(whitespace, quoted_string)),
+    ))
+    .map(|test| match test {
+        None => EnvTest::IsSet,
+        Some((_, true, value)) => EnvTest::Equals(value),
+        Some((_, false, value)) => EnvTest::NotEquals(value),
+    });
+    alt((
+        preceded(
+            keyword("hostname"),
+            cut_err(preceded(whitespace, quoted_string)),
+        )
+        .map(Condition::Hostname),
+        preceded(keyword("os"), cut_err(preceded(whitespace, quoted_string))).map(Condition::Os),
+        preceded(
+            keyword("env"),
+            cut_err((whitespace, quoted_string, env_test)),
+        )
+        .map(|((), var, test)| Condition::Env(var, test)),
+        preceded(
+            keyword("exists"),
+            cut_err(preceded(whitespace, quoted_string)),
+        )
+        .map(Condition::Exists),
+        fail.context(StrContext::Expected(StrContextValue::StringLiteral(
+            "hostname",
+        )))
+        .context(StrContext::Expected(StrContextValue::StringLiteral("os")))
+        .context(StrContext::Expected(StrContextValue::StringLiteral("env")))
+        .context(StrContext::Expected(StrContextValue::StringLiteral(
+            "exists",
+        ))),
+    ))
+    .parse_next(i)
+}
+
+/// The `{` opening a block
+fn block_open(i: &mut &str) -> ModalResult<()> {
+    (
+        whitespace,
+        '{'.context(StrContext::Expected(StrContextValue::CharLiteral('{'))),
+    )
+        .void()
         .parse_next(i)
 }
 
+/// End of a block, possibly followed by an else branch
+fn block_end(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        '}',
+        opt(preceded((space1, keyword("else")), cut_err(block_open))),
+    )
+    .map(|else_branch| match else_branch {
+        Some(()) => Directive::Else,
+        None => Directive::EndIf,
+    })
+    .parse_next(i)
+}
+
+/// Allow a `priority=N` option after a rule
+fn prioritized<'i>(
+    mut rule: impl Parser<&'i str, Directive, ErrMode<ContextError>>,
+) -> impl Parser<&'i str, Directive, ErrMode<ContextError>> {
+    move |i: &mut &'i str| {
+        let directive = rule.parse_next(i)?;
+        let priority = opt(priority).parse_next(i)?;
+        Ok(with_priority(directive, priority))
+    }
+}
+
+/// The `priority=N` option on rules
+fn priority(i: &mut &str) -> ModalResult<i32> {
+    preceded(
+        (space0, "priority="),
+        cut_err(
+            dec_int.context(StrContext::Expected(StrContextValue::Description(
+                "integer",
+            ))),
+        ),
+    )
+    .parse_next(i)
+}
+
+/// Wrap a directive with an explicit priority (if any)
+fn with_priority(directive: Directive, priority: Option<i32>) -> Directive {
+    match priority {
+        Some(priority) => Directive::Priority(priority, Box::new(directive)),
+        None => directive,
+    }
+}
+
+/// Policy for overlapping regexes
+fn match_policy(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("match-policy"),
+        cut_err(preceded(
+            whitespace,
+            alt((
+                keyword("first").value(MatchPolicy::First),
+                keyword("last").value(MatchPolicy::Last),
+                keyword("most-specific").value(MatchPolicy::MostSpecific),
+                fail.context(StrContext::Expected(StrContextValue::StringLiteral(
+                    "first",
+                )))
+                .context(StrContext::Expected(StrContextValue::StringLiteral("last")))
+                .context(StrContext::Expected(StrContextValue::StringLiteral(
+                    "most-specific",
+                ))),
+            )),
+        )),
+    )
+    .map(Directive::MatchPolicy)
+    .parse_next(i)
+}
+
+/// Strict mode, defaulting to errors
+fn strict(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("strict"),
+        opt(preceded(
+            space1,
+            cut_err(alt((
+                keyword("warn").value(Strict::Warn),
+                keyword("error").value(Strict::Error),
+                fail.context(StrContext::Expected(StrContextValue::StringLiteral("warn")))
+                    .context(StrContext::Expected(StrContextValue::StringLiteral(
+                        "error",
+                    ))),
+            ))),
+        )),
+    )
+    .map(|mode| Directive::Strict(mode.unwrap_or(Strict::Error)))
+    .parse_next(i)
+}
+
+/// Policy for keys only in the system state
+fn unknown_keys(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("unknown-keys"),
+        cut_err(preceded(whitespace, unknown_policy)),
+    )
+    .map(Directive::UnknownKeys)
+    .parse_next(i)
+}
+
+/// Policy for sections only in the system state
+fn unknown_sections(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("unknown-sections"),
+        cut_err(preceded(whitespace, unknown_policy)),
+    )
+    .map(Directive::UnknownSections)
+    .parse_next(i)
+}
+
+/// Policy for local changes that merging would overwrite
+fn conflict_policy(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("conflict-policy"),
+        cut_err(preceded(
+            whitespace,
+            alt((
+                keyword("warn").value(ConflictPolicy::Warn),
+                keyword("error").value(ConflictPolicy::Error),
+                keyword("overwrite").value(ConflictPolicy::Overwrite),
+                fail.context(StrContext::Expected(StrContextValue::StringLiteral("warn")))
+                    .context(StrContext::Expected(StrContextValue::StringLiteral(
+                        "error",
+                    )))
+                    .context(StrContext::Expected(StrContextValue::StringLiteral(
+                        "overwrite",
+                    ))),
+            )),
+        )),
+    )
+    .map(Directive::ConflictPolicy)
+    .parse_next(i)
+}
+
+/// What to output when merging fails
+fn on_error(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("on-error"),
+        cut_err(preceded(
+            whitespace,
+            alt((
+                keyword("fail").value(OnError::Fail),
+                keyword("passthrough").value(OnError::Passthrough),
+                keyword("source").value(OnError::Source),
+                fail.context(StrContext::Expected(StrContextValue::StringLiteral("fail")))
+                    .context(StrContext::Expected(StrContextValue::StringLiteral(
+                        "passthrough",
+                    )))
+                    .context(StrContext::Expected(StrContextValue::StringLiteral(
+                        "source",
+                    ))),
+            )),
+        )),
+    )
+    .map(Directive::OnError)
+    .parse_next(i)
+}
+
+/// Encoding of the files
+fn encoding(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("encoding"),
+        cut_err(preceded(
+            whitespace,
+            alt((
+                keyword("auto").value(None),
+                keyword("utf-8").value(Some(Encoding::Utf8)),
+                keyword("utf-16le").value(Some(Encoding::Utf16Le)),
+                keyword("utf-16be").value(Some(Encoding::Utf16Be)),
+                keyword("latin-1").value(Some(Encoding::Latin1)),
+                fail.context(StrContext::Expected(StrContextValue::StringLiteral("auto")))
+                    .context(StrContext::Expected(StrContextValue::StringLiteral(
+                        "utf-8",
+                    )))
+                    .context(StrContext::Expected(StrContextValue::StringLiteral(
+                        "utf-16le",
+                    )))
+                    .context(StrContext::Expected(StrContextValue::StringLiteral(
+                        "utf-16be",
+                    )))
+                    .context(StrContext::Expected(StrContextValue::StringLiteral(
+                        "latin-1",
+                    ))),
+            )),
+        )),
+    )
+    .map(Directive::Encoding)
+    .parse_next(i)
+}
+
+/// Line endings of the output
+fn line_endings(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("line-endings"),
+        cut_err(preceded(
+            whitespace,
+            alt((
+                keyword("auto").value(None),
+                keyword("lf").value(Some(LineEnding::Lf)),
+                keyword("crlf").value(Some(LineEnding::CrLf)),
+                fail.context(StrContext::Expected(StrContextValue::StringLiteral("auto")))
+                    .context(StrContext::Expected(StrContextValue::StringLiteral("lf")))
+                    .context(StrContext::Expected(StrContextValue::StringLiteral("crlf"))),
+            )),
+        )),
+    )
+    .map(Directive::LineEndings)
+    .parse_next(i)
+}
+
+/// Whether to validate the merged result
+fn validate(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("validate"),
+        cut_err(preceded(
+            whitespace,
+            alt((
+                keyword("on").value(true),
+                keyword("off").value(false),
+                fail.context(StrContext::Expected(StrContextValue::StringLiteral("on")))
+                    .context(StrContext::Expected(StrContextValue::StringLiteral("off"))),
+            )),
+        )),
+    )
+    .map(Directive::Validate)
+    .parse_next(i)
+}
+
+/// Argument to `unknown-keys` and `unknown-sections`
+fn unknown_policy(i: &mut &str) -> ModalResult<UnknownPolicy> {
+    alt((
+        keyword("keep").value(UnknownPolicy::Keep),
+        keyword("remove").value(UnknownPolicy::Remove),
+        keyword("warn").value(UnknownPolicy::Warn),
+        fail.context(StrContext::Expected(StrContextValue::StringLiteral("keep")))
+            .context(StrContext::Expected(StrContextValue::StringLiteral(
+                "remove",
+            )))
+            .context(StrContext::Expected(StrContextValue::StringLiteral("warn"))),
+    ))
+    .parse_next(i)
+}
+
+/// Policy for conditional blocks when adding
+fn add_conditions(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("add:conditions"),
+        cut_err(preceded(
+            whitespace,
+            alt((
+                keyword("skip").value(AddConditions::Skip),
+                keyword("all-branches").value(AddConditions::AllBranches),
+                fail.context(StrContext::Expected(StrContextValue::StringLiteral("skip")))
+                    .context(StrContext::Expected(StrContextValue::StringLiteral(
+                        "all-branches",
+                    ))),
+            )),
+        )),
+    )
+    .map(Directive::AddConditions)
+    .parse_next(i)
+}
+
 /// A transform statement
 fn transform(i: &mut &str) -> ModalResult<Directive> {
+    preceded(
+        keyword("transform"),
+        cut_err((whitespace, matcher_transform, whitespace, transform_tail)),
+    )
+    .map(|((), pattern, (), (transform, args))| Directive::Transform(pattern, transform, args))
+    .parse_next(i)
+}
+
+/// The transform name and arguments of a transform statement
+fn transform_tail(i: &mut &str) -> ModalResult<(String, HashMap<String, String>)> {
     (
-        "transform",
-        space1,
-        matcher_transform,
-        space1,
-        take_till(1.., [' ', '\r', '\n']),
+        take_till(1.., [' ', ';', '}', '\r', '\n']).context(StrContext::Expected(
+            StrContextValue::Description("transform name"),
+        )),
         opt(preceded(space1, separated(0.., transform_arg, space1))),
     )
-        .map(|(_, _, pattern, _, transform, args)| {
-            Directive::Transform(pattern, transform.to_owned(), args.unwrap_or_default())
-        })
+        .map(|(transform, args)| (transform.to_owned(), args.unwrap_or_default()))
         .parse_next(i)
 }
 
@@ -204,29 +936,108 @@ fn transform_arg(i: &mut &str) -> ModalResult<(String, String)> {
 
 /// Matcher for a section
 fn match_section_regex(i: &mut &str) -> ModalResult<Matcher> {
-    ("section", space1, "regex", space1, quoted_string)
-        .map(|(_, _, _, _, section)| Matcher::SectionRegex(section))
-        .parse_next(i)
+    preceded(
+        (keyword("section"), space1, keyword("regex")),
+        cut_err(preceded(whitespace, quoted_string)),
+    )
+    .map(Matcher::SectionRegex)
+    .parse_next(i)
 }
 
 /// Matcher for a section
 fn match_section(i: &mut &str) -> ModalResult<Matcher> {
-    ("section", space1, quoted_string)
-        .map(|(_, _, section)| Matcher::Section(section))
-        .parse_next(i)
+    preceded(
+        keyword("section"),
+        cut_err(preceded(whitespace, quoted_string)),
+    )
+    .map(Matcher::Section)
+    .parse_next(i)
 }
 
 /// Matcher for a regex
 fn match_regex(i: &mut &str) -> ModalResult<Matcher> {
-    ("regex", space1, quoted_string, space1, quoted_string)
-        .map(|(_, _, section, _, key)| Matcher::Regex(section, key))
-        .parse_next(i)
+    preceded(
+        keyword("regex"),
+        cut_err((whitespace, quoted_string, whitespace, quoted_string)),
+    )
+    .map(|((), section, (), key)| Matcher::Regex(section, key))
+    .parse_next(i)
 }
 
 /// Literal matcher
 fn match_literal(i: &mut &str) -> ModalResult<Matcher> {
-    (quoted_string, space1, quoted_string)
-        .map(|(section, _, key)| Matcher::Literal(section, key))
+    (quoted_string, cut_err(preceded(whitespace, quoted_string)))
+        .map(|(section, key)| Matcher::Literal(section, key))
+        .parse_next(i)
+}
+
+/// Optional qualifier on the value after a key matcher: `value regex "..."`
+fn value_qualifier(i: &mut &str) -> ModalResult<Option<String>> {
+    opt(preceded(
+        (space1, keyword("value")),
+        cut_err(preceded(
+            (
+                whitespace,
+                keyword("regex").context(StrContext::Expected(StrContextValue::StringLiteral(
+                    "regex",
+                ))),
+                whitespace,
+            ),
+            quoted_string,
+        )),
+    ))
+    .parse_next(i)
+}
+
+/// Wrap a key matcher with an optional value qualifier
+fn with_value(matcher: Matcher, value: Option<String>) -> Matcher {
+    match value {
+        Some(value) => Matcher::Value(Box::new(matcher), value),
+        None => matcher,
+    }
+}
+
+/// Any number of `except "key"` or `except regex "key regex"` clauses
+fn exceptions(i: &mut &str) -> ModalResult<Vec<KeyPattern>> {
+    repeat(
+        0..,
+        preceded(
+            (space1, keyword("except")),
+            cut_err(preceded(whitespace, key_pattern)),
+        ),
+    )
+    .parse_next(i)
+}
+
+/// Fail on an `except` clause where it isn't supported, with a clearer
+/// error than the "expected end of line" that would follow otherwise
+fn misplaced_except(i: &mut &str) -> ModalResult<()> {
+    if opt(peek((space1, keyword("except"))))
+        .parse_next(i)?
+        .is_none()
+    {
+        return Ok(());
+    }
+    space1.parse_next(i)?;
+    cut_err(fail.context(StrContext::Expected(StrContextValue::Description(
+        "end of line (except only works with ignore section and ignore regex, without value regex)",
+    ))))
+    .parse_next(i)
+}
+
+/// Wrap a matcher with exceptions (if there are any)
+fn with_exceptions(matcher: Matcher, exceptions: Vec<KeyPattern>) -> Matcher {
+    if exceptions.is_empty() {
+        matcher
+    } else {
+        Matcher::Except(Box::new(matcher), exceptions)
+    }
+}
+
+/// A key matcher (literal or regex) with an optional value qualifier
+fn match_key(i: &mut &str) -> ModalResult<Matcher> {
+    (alt((match_regex, match_literal)), value_qualifier)
+        .map(|(matcher, value)| with_value(matcher, value))
         .parse_next(i)
 }
 
@@ -235,28 +1046,356 @@ fn matcher(i: &mut &str) -> ModalResult<Matcher> {
     alt((
         match_section_regex,
         match_section,
-        match_regex,
-        match_literal,
+        match_key,
+        fail.context(StrContext::Expected(StrContextValue::StringLiteral(
+            "section",
+        )))
+        .context(StrContext::Expected(StrContextValue::StringLiteral(
+            "regex",
+        )))
+        .context(StrContext::Expected(StrContextValue::Description(
+            "quoted string",
+        ))),
     ))
     .parse_next(i)
 }
 
 /// The valid matchers for a transformer
 fn matcher_transform(i: &mut &str) -> ModalResult<Matcher> {
-    alt((match_regex, match_literal)).parse_next(i)
+    alt((
+        match_key,
+        fail.context(StrContext::Expected(StrContextValue::StringLiteral(
+            "regex",
+        )))
+        .context(StrContext::Expected(StrContextValue::Description(
+            "quoted string",
+        ))),
+    ))
+    .parse_next(i)
+}
+
+/// The section a section block applies to
+#[derive(Debug)]
+enum BlockSection {
+    Literal(String),
+    Regex(String),
+}
+
+/// A statement in a section block, before the section is filled in
+#[derive(Debug)]
+enum BlockStatement {
+    Ignore(KeyPattern, Option<String>, Vec<KeyPattern>),
+    Remove(KeyPattern, Option<String>),
+    AddRemove(KeyPattern, Option<String>),
+    AddHide(KeyPattern, Option<String>),
+    Transform(KeyPattern, Option<String>, String, HashMap<String, String>),
+    Set {
+        key: String,
+        value: String,
+        separator: Option<String>,
+    },
+    Default {
+        key: String,
+        value: String,
+        separator: Option<String>,
+    },
+}
+
+impl BlockSection {
+    /// Build a matcher for a key (literal or regex) in this section
+    fn matcher(&self, key: KeyPattern, value: Option<String>) -> Matcher {
+        // Regexes match on "section\0key", so \0 delimits the end of the
+        // section and the start of the key.
+        let matcher = match (self, key) {
+            (Self::Literal(section), KeyPattern::Literal(key)) => {
+                Matcher::Literal(section.clone(), key)
+            }
+            (Self::Literal(section), KeyPattern::Regex(key)) => {
+                Matcher::Regex(format!("^{}", regex::escape(section)), key)
+            }
+            (Self::Regex(section), KeyPattern::Literal(key)) => {
+                Matcher::Regex(section.clone(), format!("{}$", regex::escape(&key)))
+            }
+            (Self::Regex(section), KeyPattern::Regex(key)) => Matcher::Regex(section.clone(), key),
+        };
+        with_value(matcher, value)
+    }
+
+    /// Turn a statement into the equivalent directive.
+    ///
+    /// Returns `None` if the statement isn't supported for this section.
+    fn desugar(&self, statement: BlockStatement) -> Option<Directive> {
+        Some(match statement {
+            BlockStatement::Ignore(key, value, exceptions) => {
+                Directive::Ignore(with_exceptions(self.matcher(key, value), exceptions))
+            }
+            BlockStatement::Remove(key, value) => Directive::Remove(self.matcher(key, value)),
+            BlockStatement::AddRemove(key, value) => Directive::AddRemove(self.matcher(key, value)),
+            BlockStatement::AddHide(key, value) => Directive::AddHide(self.matcher(key, value)),
+            BlockStatement::Transform(key, value, transform, args) => {
+                Directive::Transform(self.matcher(key, value), transform, args)
+            }
+            BlockStatement::Set {
+                key,
+                value,
+                separator,
+            } => match self {
+                Self::Literal(section) => Directive::Set {
+                    section: section.clone(),
+                    key,
+                    value,
+                    separator,
+                },
+                Self::Regex(_) => return None,
+            },
+            BlockStatement::Default {
+                key,
+                value,
+                separator,
+            } => match self {
+                Self::Literal(section) => Directive::Default {
+                    section: section.clone(),
+                    key,
+                    value,
+                    separator,
+                },
+                Self::Regex(_) => return None,
+            },
+        })
+    }
+}
+
+/// A block of statements applying to a single section:
+///
+/// ```text
+/// section "Colors:View" { ignore "BackgroundAlternate"; transform "Foo" kde-shortcut }
+/// ```
+///
+/// Statements are separated by `;` or newlines. This is expanded into the
+/// corresponding normal directives.
+fn section_block(i: &mut &str) -> ModalResult<Vec<Directive>> {
+    let section = preceded(
+        keyword("section"),
+        cut_err(delimited(
+            whitespace,
+            alt((
+                preceded((keyword("regex"), whitespace), cut_err(quoted_string))
+                    .map(BlockSection::Regex),
+                quoted_string.map(BlockSection::Literal),
+            )),
+            block_open,
+        )),
+    )
+    .parse_next(i)?;
+    cut_err(|i: &mut &str| block_body(i, &section)).parse_next(i)
+}
+
+/// The statements of a section block, up to and including the closing `}`
+fn block_body(i: &mut &str, section: &BlockSection) -> ModalResult<Vec<Directive>> {
+    let mut result = vec![];
+    loop {
+        // Skip separators, blank lines and comments
+        let () = repeat(
+            0..,
+            alt((space1.void(), ';'.void(), newline, comment.void())),
+        )
+        .parse_next(i)?;
+        if opt('}').parse_next(i)?.is_some() {
+            return Ok(result);
+        }
+        let checkpoint = *i;
+        let statement = block_statement.parse_next(i)?;
+        let priority = opt(priority).parse_next(i)?;
+        let Some(directive) = section.desugar(statement) else {
+            *i = checkpoint;
+            return fail
+                .context(StrContext::Label("section regex"))
+                .context(StrContext::Expected(StrContextValue::Description(
+                    "statement supported in section regex blocks (not set or default)",
+                )))
+                .parse_next(i);
+        };
+        result.push(with_priority(directive, priority));
+        // Statements must be followed by a separator or the end of the block
+        peek(preceded(space0, one_of([';', '}', '\r', '\n'])))
+            .context(StrContext::Expected(StrContextValue::CharLiteral(';')))
+            .context(StrContext::Expected(StrContextValue::CharLiteral('}')))
+            .context(StrContext::Expected(StrContextValue::Description(
+                "end of line",
+            )))
+            .parse_next(i)?;
+    }
+}
+
+/// A single statement in a section block
+fn block_statement(i: &mut &str) -> ModalResult<BlockStatement> {
+    alt((
+        preceded(
+            keyword("ignore"),
+            cut_err(preceded(whitespace, block_ignore)),
+        ),
+        preceded(
+            keyword("remove"),
+            cut_err(preceded(whitespace, (key_pattern, value_qualifier))),
+        )
+        .map(|(key, value)| BlockStatement::Remove(key, value)),
+        preceded(
+            keyword("add:remove"),
+            cut_err(preceded(whitespace, (key_pattern, value_qualifier))),
+        )
+        .map(|(key, value)| BlockStatement::AddRemove(key, value)),
+        preceded(
+            keyword("add:hide"),
+            cut_err(preceded(whitespace, (key_pattern, value_qualifier))),
+        )
+        .map(|(key, value)| BlockStatement::AddHide(key, value)),
+        preceded(
+            keyword("transform"),
+            cut_err((
+                whitespace,
+                key_pattern,
+                value_qualifier,
+                whitespace,
+                transform_tail,
+            )),
+        )
+        .map(|((), key, value, (), (transform, args))| {
+            BlockStatement::Transform(key, value, transform, args)
+        }),
+        preceded(keyword("set"), cut_err(block_set_args)).map(|(key, value, separator)| {
+            BlockStatement::Set {
+                key,
+                value,
+                separator,
+            }
+        }),
+        preceded(keyword("default"), cut_err(block_set_args)).map(|(key, value, separator)| {
+            BlockStatement::Default {
+                key,
+                value,
+                separator,
+            }
+        }),
+        fail.context(StrContext::Expected(StrContextValue::CharLiteral('}')))
+            .context(StrContext::Expected(StrContextValue::StringLiteral(
+                "ignore",
+            )))
+            .context(StrContext::Expected(StrContextValue::StringLiteral(
+                "remove",
+            )))
+            .context(StrContext::Expected(StrContextValue::StringLiteral(
+                "transform",
+            )))
+            .context(StrContext::Expected(StrContextValue::StringLiteral("set")))
+            .context(StrContext::Expected(StrContextValue::StringLiteral(
+                "default",
+            )))
+            .context(StrContext::Expected(StrContextValue::StringLiteral(
+                "add:remove",
+            )))
+            .context(StrContext::Expected(StrContextValue::StringLiteral(
+                "add:hide",
+            ))),
+    ))
+    .parse_next(i)
+}
+
+/// The arguments of ignore in a section block. As outside of blocks,
+/// `except` is only supported for regexes (without `value regex`).
+fn block_ignore(i: &mut &str) -> ModalResult<BlockStatement> {
+    let (key, value) = (key_pattern, value_qualifier).parse_next(i)?;
+    let exceptions = if matches!(key, KeyPattern::Regex(_)) && value.is_none() {
+        exceptions.parse_next(i)?
+    } else {
+        misplaced_except.parse_next(i)?;
+        vec![]
+    };
+    Ok(BlockStatement::Ignore(key, value, exceptions))
+}
+
+/// A key (literal or regex) in a section block or an `except` clause
+fn key_pattern(i: &mut &str) -> ModalResult<KeyPattern> {
+    alt((
+        preceded((keyword("regex"), whitespace), cut_err(quoted_string)).map(KeyPattern::Regex),
+        quoted_string.map(KeyPattern::Literal),
+        fail.context(StrContext::Expected(StrContextValue::StringLiteral(
+            "regex",
+        )))
+        .context(StrContext::Expected(StrContextValue::Description(
+            "quoted string",
+        ))),
+    ))
+    .parse_next(i)
 }
 
-/// Quoted string value
+/// The arguments to set and default in a section block: key, value and
+/// separator
+fn block_set_args(i: &mut &str) -> ModalResult<(String, String, Option<String>)> {
+    (
+        whitespace,
+        quoted_string,
+        whitespace,
+        quoted_string,
+        opt((space1, "separator=", quoted_string).map(|(_, _, v)| v)),
+    )
+        .map(|((), key, (), value, separator)| (key, value, separator))
+        .parse_next(i)
+}
+
+/// Quoted string value.
+///
+/// Either a normal string (`"..."`) with escape sequences, or a raw string
+/// (`r"..."` or `'...'`) where the content is taken verbatim.
 fn quoted_string(i: &mut &str) -> ModalResult<String> {
-    delimited(
-        '"',
-        escaped(
-            take_till(1.., ['"', '\\']),
-            '\\',
-            alt(("\\".value("\\"), "\"".value("\""), "n".value("\n"))),
+    alt((
+        preceded("r\"", cut_err(raw_string_content('"'))),
+        preceded('\'', cut_err(raw_string_content('\''))),
+        preceded(
+            '"'.context(StrContext::Expected(StrContextValue::Description(
+                "quoted string",
+            ))),
+            cut_err(terminated(
+                escaped(
+                    take_till(1.., ['"', '\\', '\n', '\r']),
+                    '\\',
+                    escape_sequence,
+                ),
+                '"'.context(StrContext::Expected(StrContextValue::CharLiteral('"'))),
+            )),
         ),
-        '"',
+    ))
+    .parse_next(i)
+}
+
+/// The content and terminating quote of a raw string
+fn raw_string_content<'i>(quote: char) -> impl Parser<&'i str, String, ErrMode<ContextError>> {
+    terminated(
+        take_till(0.., [quote, '\n', '\r']),
+        quote.context(StrContext::Expected(StrContextValue::CharLiteral(quote))),
     )
+    .map(str::to_owned)
+}
+
+/// An escape sequence in a normal string (after the backslash)
+fn escape_sequence(i: &mut &str) -> ModalResult<char> {
+    alt((
+        '\\'.value('\\'),
+        '"'.value('"'),
+        'n'.value('\n'),
+        't'.value('\t'),
+        // Like Rust, only ASCII can be written as \xNN
+        preceded('x', take_while(2, AsChar::is_hex_digit))
+            .verify_map(|digits| u8::from_str_radix(digits, 16).ok().filter(u8::is_ascii))
+            .map(char::from),
+        delimited("u{", take_while(1..=6, AsChar::is_hex_digit), '}').verify_map(|digits| {
+            u32::from_str_radix(digits, 16)
+                .ok()
+                .and_then(char::from_u32)
+        }),
+    ))
+    .context(StrContext::Expected(StrContextValue::Description(
+        r#"escape sequence (\\, \", \n, \t, \xNN or \u{NNNN})"#,
+    )))
     .parse_next(i)
 }
 
@@ -269,7 +1408,8 @@ fn quoted_string_nl(i: &mut &str) -> ModalResult<String> {
             '\\',
             alt(("\\".value("\\"), "\"".value("\""), "n".value("\n"))),
         ),
-        alt(('\n', '\r')),
+        // Leave the newline for the top level parser, it separates directives.
+        peek(alt(('\n', '\r'))),
     )
     // Trim any trailing ws and "
     .map(|mut v: String| {
@@ -298,6 +1438,31 @@ mod tests {
 
         let res = quoted_string.parse_peek("\"invalid");
         assert!(res.is_err());
+
+        let (_, out) = quoted_string
+            .parse_peek(r#""\t\x41\u{e5}\u{1F600}""#)
+            .unwrap();
+        assert_eq!(out, "\tA\u{e5}\u{1F600}");
+        assert!(quoted_string.parse_peek(r#""\x80""#).is_err());
+        assert!(quoted_string.parse_peek(r#""\u{D800}""#).is_err());
+        assert!(quoted_string.parse_peek(r#""\d""#).is_err());
+    }
+
+    #[test]
+    fn check_raw_string() {
+        let (rem, out) = quoted_string.parse_peek(r#"r"^\d+\.[a-z]\\" x"#).unwrap();
+        assert_eq!(rem, " x");
+        assert_eq!(out, r"^\d+\.[a-z]\\");
+
+        let (rem, out) = quoted_string.parse_peek(r#"'say "\w+"' x"#).unwrap();
+        assert_eq!(rem, " x");
+        assert_eq!(out, r#"say "\w+""#);
+
+        let (_, out) = quoted_string.parse_peek("''").unwrap();
+        assert_eq!(out, "");
+
+        assert!(quoted_string.parse_peek("'unterminated\n'").is_err());
+        assert!(quoted_string.parse_peek("r\"unterminated").is_err());
     }
 
     #[test]
@@ -305,11 +1470,11 @@ mod tests {
         let (rem, out) = quoted_string_nl
             .parse_peek("\"test \\\" \\\\input\"\n")
             .unwrap();
-        assert_eq!(rem, "");
+        assert_eq!(rem, "\n");
         assert_eq!(out, "test \" \\input");
 
         let (rem, out) = quoted_string_nl.parse_peek("\"a \" b\"\n").unwrap();
-        assert_eq!(rem, "");
+        assert_eq!(rem, "\n");
         assert_eq!(out, "a \" b");
 
         let res = quoted_string_nl.parse_peek("\"invalid");
@@ -323,7 +1488,7 @@ mod tests {
             "\n"
         );
         let (rem, out) = source.parse_peek(input).unwrap();
-        assert_eq!(rem, "");
+        assert_eq!(rem, "\n");
         assert!(
             matches!(out, Directive::Source(s) if s == r#"{{ .chezmoi.sourceDir }}/{{ .chezmoi.sourceFile | trimSuffix ".tmpl" | replace "modify_" "" }}.src.ini"#)
         );
@@ -334,7 +1499,7 @@ mod tests {
             "\t  \n"
         );
         let (rem, out) = source.parse_peek(input).unwrap();
-        assert_eq!(rem, "");
+        assert_eq!(rem, "\n");
         assert!(
             matches!(out, Directive::Source(s) if s == r#"{{ .chezmoi.sourceDir }}/{{ .chezmoi.sourceFile | trimSuffix ".tmpl" | replace "modify_" "" }}.src.ini"#)
         );
@@ -420,14 +1585,37 @@ mod tests {
     add:remove regex "quux.*" "eh?"
     add:remove section "very secret"
     add:hide section "somewhat secret"
+    include "shared/kde.rules"
+    rename section "General" to "Appearance"
+    rename "General" "fontSize" to "Appearance" "font_size"
+    default "a" "b" "c" separator="="
+    if hostname "laptop" {
+        set "a" "b" "c"
+    } else {
+        if env "HOME" != "/root" {
+        }
+    }
+    if os "linux" {
+    }
+    if env "X" == "y" {
+    }
+    if env "X" {
+    }
+    if exists "/etc/hosts" {
+    }
+    add:conditions all-branches
     "#};
 
     #[test]
     fn test_parse() {
         let out = parse_config.parse(FULL_EXAMPLE).unwrap();
 
-        // Get rid of whitespace, we don't care about those
-        let out: Vec<_> = out.into_iter().filter(|v| *v != Directive::WS).collect();
+        // Get rid of whitespace and offsets, we don't care about those
+        let out: Vec<_> = out
+            .into_iter()
+            .map(|(_, v)| v)
+            .filter(|v| *v != Directive::WS)
+            .collect();
 
         assert_eq!(
             out,
@@ -463,6 +1651,402 @@ mod tests {
                 Directive::AddRemove(Matcher::Regex("quux.*".into(), "eh?".into())),
                 Directive::AddRemove(Matcher::Section("very secret".into())),
                 Directive::AddHide(Matcher::Section("somewhat secret".into())),
+                Directive::Include("shared/kde.rules".into()),
+                Directive::RenameSection {
+                    from: "General".into(),
+                    to: "Appearance".into(),
+                },
+                Directive::RenameKey {
+                    section: "General".into(),
+                    key: "fontSize".into(),
+                    new_section: "Appearance".into(),
+                    new_key: "font_size".into(),
+                },
+                Directive::Default {
+                    section: "a".into(),
+                    key: "b".into(),
+                    value: "c".into(),
+                    separator: Some("=".into()),
+                },
+                Directive::If(Condition::Hostname("laptop".into())),
+                Directive::Set {
+                    section: "a".into(),
+                    key: "b".into(),
+                    value: "c".into(),
+                    separator: None,
+                },
+                Directive::Else,
+                Directive::If(Condition::Env(
+                    "HOME".into(),
+                    EnvTest::NotEquals("/root".into())
+                )),
+                Directive::EndIf,
+                Directive::EndIf,
+                Directive::If(Condition::Os("linux".into())),
+                Directive::EndIf,
+                Directive::If(Condition::Env("X".into(), EnvTest::Equals("y".into()))),
+                Directive::EndIf,
+                Directive::If(Condition::Env("X".into(), EnvTest::IsSet)),
+                Directive::EndIf,
+                Directive::If(Condition::Exists("/etc/hosts".into())),
+                Directive::EndIf,
+                Directive::AddConditions(AddConditions::AllBranches),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_section_block() {
+        let out = parse_config
+            .parse(indoc! {r#"
+            section "Colors:View" { ignore "BackgroundAlternate"; transform "Foo" kde-shortcut }
+            section "a" {
+                # Comment
+                set "b" "c" separator="="
+
+                default "d" "e"; remove regex "f.*"
+                add:hide "g"
+            }
+            section regex "h.*" {
+                add:remove "i.j"
+                transform regex "k" unsorted-list separator=";"
+            }
+            "#})
+            .unwrap();
+        let out: Vec<_> = out
+            .into_iter()
+            .map(|(_, v)| v)
+            .filter(|v| *v != Directive::WS)
+            .collect();
+        assert_eq!(
+            out,
+            vec![
+                Directive::Ignore(Matcher::Literal(
+                    "Colors:View".into(),
+                    "BackgroundAlternate".into()
+                )),
+                Directive::Transform(
+                    Matcher::Literal("Colors:View".into(), "Foo".into()),
+                    "kde-shortcut".into(),
+                    HashMap::new()
+                ),
+                Directive::Set {
+                    section: "a".into(),
+                    key: "b".into(),
+                    value: "c".into(),
+                    separator: Some("=".into()),
+                },
+                Directive::Default {
+                    section: "a".into(),
+                    key: "d".into(),
+                    value: "e".into(),
+                    separator: None,
+                },
+                Directive::Remove(Matcher::Regex("^a".into(), "f.*".into())),
+                Directive::AddHide(Matcher::Literal("a".into(), "g".into())),
+                Directive::AddRemove(Matcher::Regex("h.*".into(), "i\\.j$".into())),
+                Directive::Transform(
+                    Matcher::Regex("h.*".into(), "k".into()),
+                    "unsorted-list".into(),
+                    HashMap::from([("separator".into(), ";".into())])
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_value_matcher() {
+        let out = parse_config
+            .parse(indoc! {r#"
+            ignore regex "Recent.*" ".*" value regex "^/home/"
+            remove "a" "b" value regex 'x\d+'
+            transform "c" "d" value regex "e" kde-shortcut
+            section "f" { add:hide regex "g.*" value regex "h" }
+            "#})
+            .unwrap();
+        let out: Vec<_> = out
+            .into_iter()
+            .map(|(_, v)| v)
+            .filter(|v| *v != Directive::WS)
+            .collect();
+        let value = |matcher, value: &str| Matcher::Value(Box::new(matcher), value.into());
+        assert_eq!(
+            out,
+            vec![
+                Directive::Ignore(value(
+                    Matcher::Regex("Recent.*".into(), ".*".into()),
+                    "^/home/"
+                )),
+                Directive::Remove(value(Matcher::Literal("a".into(), "b".into()), "x\\d+")),
+                Directive::Transform(
+                    value(Matcher::Literal("c".into(), "d".into()), "e"),
+                    "kde-shortcut".into(),
+                    HashMap::new()
+                ),
+                Directive::AddHide(value(Matcher::Regex("^f".into(), "g.*".into()), "h")),
+            ]
+        );
+        assert!(
+            parse_config
+                .parse("ignore section \"a\" value regex \"b\"")
+                .is_err()
+        );
+        assert!(
+            parse_config
+                .parse("ignore \"a\" \"b\" value \"c\"")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_except() {
+        let out = parse_config
+            .parse(indoc! {r#"
+            ignore section "MainWindow" except "ToolBarsMovable"
+            ignore regex "a" "b.*" except "bc" except regex "bd.*"
+            section "c" { ignore regex "d.*" except "de" }
+            "#})
+            .unwrap();
+        let out: Vec<_> = out
+            .into_iter()
+            .map(|(_, v)| v)
+            .filter(|v| *v != Directive::WS)
+            .collect();
+        let except = |matcher, exceptions| Matcher::Except(Box::new(matcher), exceptions);
+        assert_eq!(
+            out,
+            vec![
+                Directive::Ignore(except(
+                    Matcher::Section("MainWindow".into()),
+                    vec![KeyPattern::Literal("ToolBarsMovable".into())]
+                )),
+                Directive::Ignore(except(
+                    Matcher::Regex("a".into(), "b.*".into()),
+                    vec![
+                        KeyPattern::Literal("bc".into()),
+                        KeyPattern::Regex("bd.*".into())
+                    ]
+                )),
+                Directive::Ignore(except(
+                    Matcher::Regex("^c".into(), "d.*".into()),
+                    vec![KeyPattern::Literal("de".into())]
+                )),
+            ]
+        );
+        assert!(
+            parse_config
+                .parse("remove section \"a\" except \"b\"")
+                .is_err()
+        );
+        assert!(parse_config.parse("ignore section \"a\" except").is_err());
+        for misplaced in [
+            "ignore \"a\" \"b\" except \"c\"",
+            "ignore regex \"a\" \"b.*\" value regex \"x\" except \"c\"",
+            "section \"a\" { ignore \"b\" except \"c\" }",
+            "section \"a\" { ignore regex \"b.*\" value regex \"x\" except \"c\" }",
+        ] {
+            assert!(parse_config.parse(misplaced).is_err(), "{misplaced}");
+        }
+    }
+
+    #[test]
+    fn test_priority() {
+        let out = parse_config
+            .parse(indoc! {r#"
+            match-policy most-specific
+            ignore regex "a" "b.*" priority=10
+            remove "a" "c" priority=-1
+            transform regex "a" "d.*" unsorted-list separator="," priority=2
+            section "e" { ignore "f" priority=3; remove "g" }
+            "#})
+            .unwrap();
+        let out: Vec<_> = out
+            .into_iter()
+            .map(|(_, v)| v)
+            .filter(|v| *v != Directive::WS)
+            .collect();
+        assert_eq!(
+            out,
+            vec![
+                Directive::MatchPolicy(MatchPolicy::MostSpecific),
+                Directive::Priority(
+                    10,
+                    Box::new(Directive::Ignore(Matcher::Regex("a".into(), "b.*".into())))
+                ),
+                Directive::Priority(
+                    -1,
+                    Box::new(Directive::Remove(Matcher::Literal("a".into(), "c".into())))
+                ),
+                Directive::Priority(
+                    2,
+                    Box::new(Directive::Transform(
+                        Matcher::Regex("a".into(), "d.*".into()),
+                        "unsorted-list".into(),
+                        HashMap::from([("separator".into(), ",".into())])
+                    ))
+                ),
+                Directive::Priority(
+                    3,
+                    Box::new(Directive::Ignore(Matcher::Literal("e".into(), "f".into())))
+                ),
+                Directive::Remove(Matcher::Literal("e".into(), "g".into())),
+            ]
+        );
+        assert!(parse_config.parse("ignore \"a\" \"b\" priority=x").is_err());
+        assert!(parse_config.parse("match-policy best").is_err());
+    }
+
+    #[test]
+    fn test_strict() {
+        let parse = |src| {
+            parse_config
+                .parse(src)
+                .unwrap()
+                .into_iter()
+                .map(|(_, v)| v)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(parse("strict"), vec![Directive::Strict(Strict::Error)]);
+        assert_eq!(parse("strict warn"), vec![Directive::Strict(Strict::Warn)]);
+        assert_eq!(
+            parse("strict error"),
+            vec![Directive::Strict(Strict::Error)]
+        );
+        assert!(parse_config.parse("strict always").is_err());
+        assert!(parse_config.parse("strictly").is_err());
+    }
+
+    #[test]
+    fn test_unknown() {
+        let out = parse_config
+            .parse("unknown-keys keep\nunknown-sections warn\nunknown-keys remove")
+            .unwrap();
+        let out: Vec<_> = out.into_iter().map(|(_, v)| v).collect();
+        assert_eq!(
+            out,
+            vec![
+                Directive::UnknownKeys(UnknownPolicy::Keep),
+                Directive::UnknownSections(UnknownPolicy::Warn),
+                Directive::UnknownKeys(UnknownPolicy::Remove),
+            ]
+        );
+        assert!(parse_config.parse("unknown-keys").is_err());
+        assert!(parse_config.parse("unknown-sections ignore").is_err());
+    }
+
+    #[test]
+    fn test_conflict_policy() {
+        let out = parse_config
+            .parse("conflict-policy error\nconflict-policy overwrite\nconflict-policy warn")
+            .unwrap();
+        let out: Vec<_> = out.into_iter().map(|(_, v)| v).collect();
+        assert_eq!(
+            out,
+            vec![
+                Directive::ConflictPolicy(ConflictPolicy::Error),
+                Directive::ConflictPolicy(ConflictPolicy::Overwrite),
+                Directive::ConflictPolicy(ConflictPolicy::Warn),
+            ]
+        );
+        assert!(parse_config.parse("conflict-policy").is_err());
+        assert!(parse_config.parse("conflict-policy keep").is_err());
+    }
+
+    #[test]
+    fn test_on_error() {
+        let out = parse_config
+            .parse("on-error fail\non-error passthrough\non-error source")
+            .unwrap();
+        let out: Vec<_> = out.into_iter().map(|(_, v)| v).collect();
+        assert_eq!(
+            out,
+            vec![
+                Directive::OnError(OnError::Fail),
+                Directive::OnError(OnError::Passthrough),
+                Directive::OnError(OnError::Source),
+            ]
+        );
+        assert!(parse_config.parse("on-error").is_err());
+        assert!(parse_config.parse("on-error ignore").is_err());
+    }
+
+    #[test]
+    fn test_encoding() {
+        let out = parse_config
+            .parse("encoding auto\nencoding utf-16le\nencoding latin-1")
+            .unwrap();
+        let out: Vec<_> = out.into_iter().map(|(_, v)| v).collect();
+        assert_eq!(
+            out,
+            vec![
+                Directive::Encoding(None),
+                Directive::Encoding(Some(Encoding::Utf16Le)),
+                Directive::Encoding(Some(Encoding::Latin1)),
+            ]
+        );
+        assert!(parse_config.parse("encoding").is_err());
+        assert!(parse_config.parse("encoding utf-16").is_err());
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let out = parse_config
+            .parse("line-endings auto\nline-endings lf\nline-endings crlf")
+            .unwrap();
+        let out: Vec<_> = out.into_iter().map(|(_, v)| v).collect();
+        assert_eq!(
+            out,
+            vec![
+                Directive::LineEndings(None),
+                Directive::LineEndings(Some(LineEnding::Lf)),
+                Directive::LineEndings(Some(LineEnding::CrLf)),
+            ]
+        );
+        assert!(parse_config.parse("line-endings").is_err());
+        assert!(parse_config.parse("line-endings cr").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let out = parse_config.parse("validate on\nvalidate off").unwrap();
+        let out: Vec<_> = out.into_iter().map(|(_, v)| v).collect();
+        assert_eq!(
+            out,
+            vec![Directive::Validate(true), Directive::Validate(false)]
+        );
+        assert!(parse_config.parse("validate").is_err());
+        assert!(parse_config.parse("validate yes").is_err());
+    }
+
+    #[test]
+    fn test_section_block_errors() {
+        assert!(parse_config.parse("section \"a\" { ignore \"b\"").is_err());
+        assert!(
+            parse_config
+                .parse("section \"a\" { ignore \"b\" \"c\" }")
+                .is_err()
+        );
+        assert!(parse_config.parse("section \"a\" { source auto }").is_err());
+        assert!(
+            parse_config
+                .parse("section regex \"a\" { set \"b\" \"c\" }")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_offsets() {
+        let out = parse_config
+            .parse("source auto\n\n# Comment\r\ninclude \"a\"\n")
+            .unwrap();
+        assert_eq!(
+            out,
+            vec![
+                (0, Directive::SourceAutoEnv),
+                (12, Directive::WS),
+                (13, Directive::WS),
+                (24, Directive::Include("a".into())),
+                (36, Directive::WS),
             ]
         );
     }
@@ -476,8 +2060,12 @@ mod tests {
             )
             .unwrap();
 
-        // Get rid of whitespace, we don't care about those
-        let out: Vec<_> = out.into_iter().filter(|v| *v != Directive::WS).collect();
+        // Get rid of whitespace and offsets, we don't care about those
+        let out: Vec<_> = out
+            .into_iter()
+            .map(|(_, v)| v)
+            .filter(|v| *v != Directive::WS)
+            .collect();
 
         assert_eq!(
             out,
@@ -0,0 +1,178 @@
+//! Ordering of rules by explicit priority and match policy.
+//!
+//! ini-merge picks the first regex that matches a key, and the last literal
+//! action added for a key. Rules are sorted so that these pick the rule that
+//! should win.
+use super::diagnostics::Origin;
+use super::parser::Directive;
+use super::parser::MatchPolicy;
+use super::parser::Matcher;
+use std::cmp::Reverse;
+
+/// How strongly a rule applies, compared to other rules of the same kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) struct Rank {
+    /// The explicit priority (default 0)
+    pub(super) priority: i32,
+    /// Position in the config file
+    index: usize,
+}
+
+impl Rank {
+    #[cfg(test)]
+    pub(super) const fn new(priority: i32, index: usize) -> Self {
+        Self { priority, index }
+    }
+}
+
+/// A directive together with its rank
+#[derive(Debug)]
+pub(super) struct Rule {
+    pub(super) rank: Rank,
+    /// Where the directive was written
+    pub(super) origin: Origin,
+    pub(super) directive: Directive,
+}
+
+/// Sort directives in the order their actions should be added to the builder.
+///
+/// Higher priorities come first, ties are sorted according to the match policy
+/// (the last `match-policy` directive, `first` if there is none). The match
+/// policy is also returned, as it affects diagnostics.
+pub(super) fn order(directives: Vec<(Origin, Directive)>) -> (Vec<Rule>, Option<MatchPolicy>) {
+    let policy = directives
+        .iter()
+        .rev()
+        .find_map(|(_, directive)| match directive {
+            Directive::MatchPolicy(policy) => Some(*policy),
+            _ => None,
+        });
+    let mut rules: Vec<Rule> = directives
+        .into_iter()
+        .enumerate()
+        .map(|(index, (origin, directive))| match directive {
+            Directive::Priority(priority, directive) => Rule {
+                rank: Rank { priority, index },
+                origin,
+                directive: *directive,
+            },
+            directive => Rule {
+                rank: Rank { priority: 0, index },
+                origin,
+                directive,
+            },
+        })
+        .collect();
+    match policy.unwrap_or(MatchPolicy::First) {
+        MatchPolicy::First => {
+            rules.sort_by_key(|rule| (Reverse(rule.rank.priority), rule.rank.index));
+        }
+        MatchPolicy::Last => rules.sort_by_key(|rule| Reverse(rule.rank)),
+        MatchPolicy::MostSpecific => rules.sort_by_key(|rule| {
+            (
+                Reverse(rule.rank.priority),
+                Reverse(directive_specificity(&rule.directive)),
+                rule.rank.index,
+            )
+        }),
+    }
+    (rules, policy)
+}
+
+/// The specificity of the matcher of a directive
+fn directive_specificity(directive: &Directive) -> usize {
+    match directive {
+        Directive::Ignore(matcher)
+        | Directive::Transform(matcher, _, _)
+        | Directive::Remove(matcher)
+        | Directive::AddRemove(matcher)
+        | Directive::AddHide(matcher) => matcher_specificity(matcher),
+        _ => 0,
+    }
+}
+
+fn matcher_specificity(matcher: &Matcher) -> usize {
+    match matcher {
+        Matcher::SectionRegex(section) => specificity(section),
+        Matcher::Regex(section, key) => specificity(section) + specificity(key),
+        Matcher::Value(matcher, _) | Matcher::Except(matcher, _) => matcher_specificity(matcher),
+        // Only regexes are ordered
+        Matcher::Section(_) | Matcher::Literal(_, _) => 0,
+    }
+}
+
+/// Rough measure of how specific a regex is: the number of characters outside
+/// of character classes that match literally.
+fn specificity(regex: &str) -> usize {
+    let mut count = 0;
+    let mut in_class = false;
+    let mut chars = regex.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            // Escaped punctuation is literal, escaped letters are classes
+            // like \d or \w
+            '\\' => {
+                if chars.next().is_some_and(|c| !c.is_alphanumeric()) && !in_class {
+                    count += 1;
+                }
+            }
+            '[' => in_class = true,
+            ']' => in_class = false,
+            '.' | '+' | '*' | '?' | '(' | ')' | '|' | '{' | '}' | '^' | '$' => (),
+            _ if in_class => (),
+            _ => count += 1,
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn ignore_regex(section: &str, key: &str) -> Directive {
+        Directive::Ignore(Matcher::Regex(section.into(), key.into()))
+    }
+
+    fn ordered(directives: Vec<Directive>) -> Vec<usize> {
+        order(
+            directives
+                .into_iter()
+                .map(|directive| (Origin::default(), directive))
+                .collect(),
+        )
+        .0
+        .into_iter()
+        .map(|rule| rule.rank.index)
+        .collect()
+    }
+
+    #[test]
+    fn check_specificity() {
+        assert_eq!(specificity("abc"), 3);
+        assert_eq!(specificity("a.*"), 1);
+        assert_eq!(specificity(r"a\.b\d+"), 3);
+        assert_eq!(specificity("[abc]+x(y|z)"), 3);
+    }
+
+    #[test]
+    fn check_order() {
+        let rules = || {
+            vec![
+                ignore_regex("a", ".*"),
+                Directive::Priority(5, Box::new(ignore_regex("a", "b"))),
+                ignore_regex("a", "bcd.*"),
+            ]
+        };
+        assert_eq!(ordered(rules()), vec![1, 0, 2]);
+
+        let mut last = rules();
+        last.push(Directive::MatchPolicy(MatchPolicy::Last));
+        assert_eq!(ordered(last), vec![1, 3, 2, 0]);
+
+        let mut most_specific = rules();
+        most_specific.insert(0, Directive::MatchPolicy(MatchPolicy::MostSpecific));
+        assert_eq!(ordered(most_specific), vec![2, 3, 1, 0]);
+    }
+}
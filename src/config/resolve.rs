@@ -0,0 +1,681 @@
+//! Resolution of matchers that depend on the contents of the files
+//! (`value regex` and `except`).
+//!
+//! ini-merge only matches on section and key names, and has no way to exempt
+//! keys from a match. Such matchers are instead turned into literal matches
+//! for the keys currently in the files.
+use super::cache;
+use super::parser::KeyPattern;
+use super::parser::MatchPolicy;
+use super::parser::Matcher;
+use super::priority::Rank;
+use crate::ini_state::IniState;
+use anyhow::Context;
+use anyhow::anyhow;
+use regex::Regex;
+use regex::RegexSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// Matches section and key names, like ini-merge does
+#[derive(Debug)]
+pub(super) enum KeyMatcher<'a> {
+    Section(&'a str),
+    SectionRegex(Regex),
+    Literal(&'a str, &'a str),
+    Regex(Regex),
+}
+
+impl<'a> KeyMatcher<'a> {
+    pub(super) fn new(matcher: &'a Matcher) -> anyhow::Result<Self> {
+        match matcher {
+            Matcher::Section(section) => Ok(Self::Section(section)),
+            Matcher::SectionRegex(section) => Ok(Self::SectionRegex(
+                cache::regex(section)
+                    .with_context(|| format!("Invalid section regex \"{section}\""))?,
+            )),
+            Matcher::Literal(section, key) => Ok(Self::Literal(section, key)),
+            Matcher::Regex(section, key) => Self::regex(section, key),
+            Matcher::Value(_, _) | Matcher::Except(_, _) => {
+                Err(anyhow!("Can't combine value regex and except"))
+            }
+        }
+    }
+
+    /// Same format as ini-merge uses for regex actions
+    fn regex(section: &str, key: &str) -> anyhow::Result<Self> {
+        Ok(Self::Regex(
+            cache::regex(&format!("(?:{section})\0(?:{key})"))
+                .with_context(|| format!("Invalid regex \"{section}\" \"{key}\""))?,
+        ))
+    }
+
+    pub(super) fn is_match(&self, section: &str, key: &str) -> bool {
+        match self {
+            Self::Section(s) => *s == section,
+            Self::SectionRegex(re) => re.is_match(section),
+            Self::Literal(s, k) => *s == section && *k == key,
+            Self::Regex(re) => re.is_match(&format!("{section}\0{key}")),
+        }
+    }
+}
+
+/// Matches key names for `except`
+#[derive(Debug)]
+enum Exception<'a> {
+    Literal(&'a str),
+    Regex(Regex),
+}
+
+impl<'a> Exception<'a> {
+    fn new(pattern: &'a KeyPattern) -> anyhow::Result<Self> {
+        match pattern {
+            KeyPattern::Literal(key) => Ok(Self::Literal(key)),
+            // Anchored at the start like key regexes are (right after the \0)
+            KeyPattern::Regex(key) => Ok(Self::Regex(
+                cache::regex(&format!("^(?:{key})"))
+                    .with_context(|| format!("Invalid except regex \"{key}\""))?,
+            )),
+        }
+    }
+
+    fn is_match(&self, key: &str) -> bool {
+        match self {
+            Self::Literal(k) => *k == key,
+            Self::Regex(re) => re.is_match(key),
+        }
+    }
+}
+
+/// A regex rule, kept for diagnostics
+#[derive(Debug)]
+struct RegexRule {
+    section: String,
+    key: String,
+    description: String,
+    rank: Rank,
+}
+
+impl std::fmt::Display for RegexRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}", self.description)?;
+        if self.rank.priority != 0 {
+            write!(f, " priority={}", self.rank.priority)?;
+        }
+        write!(f, "`")
+    }
+}
+
+/// Entries only in the system state that no rule applies to
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(super) struct Unknown {
+    /// Keys in sections that also exist in the source state
+    pub(super) keys: Vec<(String, String)>,
+    /// Sections that only exist in the system state
+    pub(super) sections: Vec<UnknownSection>,
+}
+
+/// A section that only exists in the system state
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct UnknownSection {
+    pub(super) name: String,
+    /// Keys in the section that no rule applies to
+    pub(super) keys: Vec<String>,
+    /// True if no rule applies to any key in the section
+    pub(super) untouched: bool,
+}
+
+/// Resolves matchers against the contents of the files.
+///
+/// To keep the priority of the kind of rule a resolved matcher came from
+/// (section > literal > regex), this also needs to know about all actions
+/// added to the builder. Rules must be processed in the order from
+/// [`super::priority::order`].
+#[derive(Debug)]
+pub(super) struct Resolver<'a, A> {
+    /// The files, in priority order for looking up values
+    states: &'a [&'a IniState],
+    /// The explicit match policy, if any
+    policy: Option<MatchPolicy>,
+    /// Look for overlapping regexes at all (not done on the fast path)
+    report_overlaps: bool,
+    /// Warn about overlapping regexes
+    warn_on_multiple_matches: bool,
+    /// Sections with literal actions
+    sections: HashMap<String, Rank>,
+    /// Section regexes added so far
+    section_regexes: Vec<String>,
+    /// Keys with literal actions
+    literal_keys: HashMap<(String, String), Rank>,
+    /// Key regexes added so far, in order
+    regexes: Vec<RegexRule>,
+    /// Keys resolved from section matches with exceptions
+    section_keys: Vec<(Rank, String, String, A)>,
+    /// Keys resolved from regex matches with exceptions, with the number of
+    /// regexes added before them
+    regex_keys: Vec<(usize, String, String, A)>,
+}
+
+impl<'a, A> Resolver<'a, A> {
+    pub(super) fn new(states: &'a [&'a IniState], policy: Option<MatchPolicy>) -> Self {
+        Self {
+            states,
+            policy,
+            report_overlaps: false,
+            warn_on_multiple_matches: true,
+            sections: HashMap::new(),
+            section_regexes: vec![],
+            literal_keys: HashMap::new(),
+            regexes: vec![],
+            section_keys: vec![],
+            regex_keys: vec![],
+        }
+    }
+
+    /// Report keys matched by several regexes from [`Resolver::finish`]. This
+    /// checks every key against every regex, so it is only done when asked
+    /// for (`--check`, `--explain` and `--add`), not when merging.
+    pub(super) fn report_overlaps(&mut self) {
+        self.report_overlaps = true;
+    }
+
+    /// Don't warn about overlapping regexes
+    pub(super) fn no_warn(&mut self) {
+        self.warn_on_multiple_matches = false;
+    }
+
+    /// Check if a literal section action should be added, and record it
+    pub(super) fn section(&mut self, section: &str, rank: Rank) -> bool {
+        match self.sections.get(section) {
+            Some(existing) if *existing > rank => false,
+            _ => {
+                self.sections.insert(section.to_owned(), rank);
+                true
+            }
+        }
+    }
+
+    /// Record that a section regex action was added
+    pub(super) fn section_regex(&mut self, section: &str) {
+        self.section_regexes.push(section.to_owned());
+    }
+
+    /// Check if a literal action for a key should be added, and record it.
+    ///
+    /// A literal action replaces any earlier one for the same key, so this
+    /// returns false if a rule with a higher rank was already added.
+    pub(super) fn literal(&mut self, section: &str, key: &str, rank: Rank) -> bool {
+        let entry = (section.to_owned(), key.to_owned());
+        match self.literal_keys.get(&entry) {
+            Some(existing) if *existing > rank => false,
+            _ => {
+                self.literal_keys.insert(entry, rank);
+                true
+            }
+        }
+    }
+
+    /// Record that a regex action was added
+    pub(super) fn regex(&mut self, section: &str, key: &str, description: String, rank: Rank) {
+        self.regexes.push(RegexRule {
+            section: section.to_owned(),
+            key: key.to_owned(),
+            description,
+            rank,
+        });
+    }
+
+    /// Find all keys matched by `matcher` with a value matching `value_regex`.
+    ///
+    /// The value is taken from the first state that has the key (i.e. the
+    /// system state takes priority over the source state). Keys without a
+    /// value match as the empty string. The caller is expected to add literal
+    /// actions for the returned keys.
+    pub(super) fn value(
+        &mut self,
+        matcher: &Matcher,
+        value_regex: &str,
+        rank: Rank,
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        let value_regex = cache::regex(value_regex)
+            .with_context(|| format!("Invalid value regex \"{value_regex}\""))?;
+        let states = self.states;
+        let mut result = self.matching_keys(matcher, |section, key| {
+            let value = states
+                .iter()
+                .find_map(|state| state.value(section, key))
+                .flatten()
+                .unwrap_or_default();
+            value_regex.is_match(value)
+        })?;
+        result.retain(|(section, key)| self.literal(section, key, rank));
+        Ok(result)
+    }
+
+    /// Resolve a section or regex matcher with exceptions. The resulting
+    /// literal actions are returned by [`Resolver::finish`].
+    pub(super) fn except(
+        &mut self,
+        matcher: &Matcher,
+        exceptions: &[KeyPattern],
+        action: A,
+        rank: Rank,
+    ) -> anyhow::Result<()>
+    where
+        A: Clone,
+    {
+        let exceptions = exceptions
+            .iter()
+            .map(Exception::new)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let keys = self.matching_keys(matcher, |_, key| {
+            !exceptions.iter().any(|exception| exception.is_match(key))
+        })?;
+        match matcher {
+            Matcher::Section(_) | Matcher::SectionRegex(_) => {
+                self.section_keys.extend(
+                    keys.into_iter()
+                        .map(|(section, key)| (rank, section, key, action.clone())),
+                );
+            }
+            Matcher::Regex(_, _) => {
+                let regexes = self.regexes.len();
+                self.regex_keys.extend(
+                    keys.into_iter()
+                        .map(|(section, key)| (regexes, section, key, action.clone())),
+                );
+            }
+            Matcher::Literal(section, key) => {
+                return Err(anyhow!(
+                    "except can't be used with an exact key match (\"{section}\" \"{key}\")"
+                ));
+            }
+            Matcher::Value(_, _) | Matcher::Except(_, _) => {
+                return Err(anyhow!("Can't combine value regex and except"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the literal actions to add for matchers with exceptions, and
+    /// report overlapping regexes (if enabled).
+    ///
+    /// The actions must be added to the builder after all other actions. Keys
+    /// from section matches replace any literal actions, keys from regex
+    /// matches are dropped if a literal action or an earlier regex also
+    /// matches.
+    pub(super) fn finish(mut self) -> anyhow::Result<Vec<(String, String, A)>> {
+        // Compiling the regexes is not free, skip it when it isn't needed
+        let regexes = if self.regex_keys.is_empty() && !self.report_overlaps {
+            None
+        } else {
+            self.regex_set()
+        };
+        let mut result = vec![];
+        for (before, section, key, action) in std::mem::take(&mut self.regex_keys) {
+            let shadowed = self
+                .literal_keys
+                .contains_key(&(section.clone(), key.clone()))
+                || regexes.as_ref().is_some_and(|regexes| {
+                    regexes
+                        .matches(&format!("{section}\0{key}"))
+                        .iter()
+                        .any(|idx| idx < before)
+                });
+            if !shadowed {
+                result.push((section, key, action));
+            }
+        }
+        // The highest ranked match for a key must be added last
+        let mut section_keys = std::mem::take(&mut self.section_keys);
+        section_keys.sort_by_key(|(rank, ..)| *rank);
+        result.extend(
+            section_keys
+                .into_iter()
+                .map(|(_, section, key, action)| (section, key, action)),
+        );
+
+        if let Some(regexes) = regexes.filter(|_| self.report_overlaps) {
+            let resolved: HashSet<_> = result
+                .iter()
+                .map(|(s, k, _)| (s.as_str(), k.as_str()))
+                .collect();
+            for (level, message) in self.overlaps(&regexes, &resolved) {
+                log::log!(level, "{message}");
+            }
+        }
+        Ok(result)
+    }
+
+    /// Describe keys matched by multiple regexes, where a regex decides the
+    /// action.
+    ///
+    /// This is a warning if the winner was only picked by the order in the
+    /// file, otherwise it is only logged at info level.
+    fn overlaps(
+        &self,
+        regexes: &RegexSet,
+        resolved: &HashSet<(&str, &str)>,
+    ) -> Vec<(log::Level, String)> {
+        let mut result = vec![];
+        if self.regexes.len() < 2 {
+            return result;
+        }
+        let section_regexes = self.compile_section_regexes();
+        let mut seen = HashSet::new();
+        for (section, key) in self.states.iter().flat_map(|state| state.keys()) {
+            if !seen.insert((section, key))
+                || resolved.contains(&(section, key))
+                || self.has_section_rule(section, &section_regexes)
+                || self
+                    .literal_keys
+                    .contains_key(&(section.to_owned(), key.to_owned()))
+            {
+                continue;
+            }
+            let matching: Vec<_> = regexes
+                .matches(&format!("{section}\0{key}"))
+                .iter()
+                .map(|idx| &self.regexes[idx])
+                .collect();
+            let [winner, runner_up, ..] = matching[..] else {
+                continue;
+            };
+            let losers = matching[1..]
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            let message =
+                format!("Overlapping rules for {section}/{key}: using {winner}, not {losers}");
+            if self.policy.is_none() && winner.rank.priority == runner_up.rank.priority {
+                if self.warn_on_multiple_matches {
+                    result.push((
+                        log::Level::Warn,
+                        format!(
+                            "{message}. Use priority=N or match-policy to choose, or \
+                             no-warn-multiple-key-matches if this is intentional"
+                        ),
+                    ));
+                }
+            } else {
+                result.push((log::Level::Info, message));
+            }
+        }
+        result
+    }
+
+    /// Find the entries in `system` that don't exist in `source`, and that
+    /// no rule applies to (for `unknown-keys` and `unknown-sections`).
+    ///
+    /// Keys before the first section are always treated as keys, never as a
+    /// section.
+    pub(super) fn unknown(&self, system: &IniState, source: &IniState) -> Unknown {
+        let regexes = self.regex_set();
+        let section_regexes = self.compile_section_regexes();
+        let resolved: HashSet<_> = self
+            .section_keys
+            .iter()
+            .map(|(_, s, k, _)| (s.as_str(), k.as_str()))
+            .chain(
+                self.regex_keys
+                    .iter()
+                    .map(|(_, s, k, _)| (s.as_str(), k.as_str())),
+            )
+            .collect();
+        let has_key_rule = |section: &str, key: &str| {
+            self.literal_keys
+                .contains_key(&(section.to_owned(), key.to_owned()))
+                || resolved.contains(&(section, key))
+                || regexes
+                    .as_ref()
+                    .is_some_and(|regexes| regexes.is_match(&format!("{section}\0{key}")))
+        };
+        let source_sections: HashSet<_> = source.sections().collect();
+        let mut result = Unknown::default();
+        for section in system.sections() {
+            if source_sections.contains(section) || self.has_section_rule(section, &section_regexes)
+            {
+                continue;
+            }
+            result.sections.push(UnknownSection {
+                name: section.to_owned(),
+                keys: vec![],
+                untouched: true,
+            });
+        }
+        for (section, key) in system.keys() {
+            if source.has_key(section, key) || self.has_section_rule(section, &section_regexes) {
+                continue;
+            }
+            let has_rule = has_key_rule(section, key);
+            match result.sections.iter_mut().find(|s| s.name == section) {
+                Some(unknown) if has_rule => unknown.untouched = false,
+                Some(unknown) => unknown.keys.push(key.to_owned()),
+                None if has_rule => (),
+                None => result.keys.push((section.to_owned(), key.to_owned())),
+            }
+        }
+        result
+    }
+
+    /// Compile the key regexes added so far into a set matching
+    /// `section\0key`, with the same indices as `self.regexes`. Invalid
+    /// regexes are reported when building the actions.
+    fn regex_set(&self) -> Option<RegexSet> {
+        RegexSet::new(
+            self.regexes
+                .iter()
+                .map(|rule| format!("(?:{})\0(?:{})", rule.section, rule.key)),
+        )
+        .ok()
+    }
+
+    /// Compile the section regexes added so far, see [`Self::compile_regexes`]
+    fn compile_section_regexes(&self) -> Vec<Regex> {
+        self.section_regexes
+            .iter()
+            .filter_map(|section| cache::regex(section).ok())
+            .collect()
+    }
+
+    /// Check if a section action applies to a section
+    fn has_section_rule(&self, section: &str, section_regexes: &[Regex]) -> bool {
+        self.sections.contains_key(section) || section_regexes.iter().any(|re| re.is_match(section))
+    }
+
+    /// Find all keys (in any of the files) matched by `matcher` for which
+    /// `filter` returns true
+    fn matching_keys(
+        &self,
+        matcher: &Matcher,
+        mut filter: impl FnMut(&str, &str) -> bool,
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        let names = KeyMatcher::new(matcher)?;
+        let mut seen = HashSet::new();
+        let mut result = vec![];
+        for (section, key) in self.states.iter().flat_map(|state| state.keys()) {
+            if seen.insert((section, key)) && names.is_match(section, key) && filter(section, key) {
+                result.push((section.to_owned(), key.to_owned()));
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ini_merge::OUTSIDE_SECTION;
+    use pretty_assertions::assert_eq;
+
+    fn keys(v: &[(&str, &str)]) -> Vec<(String, String)> {
+        v.iter()
+            .map(|(s, k)| (s.to_string(), k.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn check_value() {
+        let system = IniState::new("[a]\nRecent1=/home/x\nRecent2=/tmp/y\nOther=/home/z\n");
+        let source = IniState::new("[a]\nRecent1=/tmp/x\nRecent3=/home/w\n[b]\nRecent4=/home\n");
+        let states = [&system, &source];
+        let mut resolver = Resolver::<()>::new(&states, None);
+        // A higher ranked literal for the key was already added
+        assert!(resolver.literal("a", "Recent3", Rank::new(1, 0)));
+        let matcher = Matcher::Regex("a".into(), "Recent.*".into());
+        let out = resolver
+            .value(&matcher, "^/home/", Rank::new(0, 1))
+            .unwrap();
+        assert_eq!(out, keys(&[("a", "Recent1")]));
+
+        let matcher = Matcher::Literal("a".into(), "Other".into());
+        let out = resolver.value(&matcher, "^/tmp/", Rank::new(0, 2)).unwrap();
+        assert_eq!(out, vec![]);
+    }
+
+    #[test]
+    fn check_except() {
+        let system = IniState::new("[a]\nb=1\nc=2\nd=3\n[x]\nb=4\n");
+        let source = IniState::new("[a]\ne=5\n[y]\nz=6\n");
+        let states = [&system, &source];
+        let mut resolver = Resolver::new(&states, None);
+        resolver.literal("x", "b", Rank::new(0, 0));
+        resolver
+            .except(
+                &Matcher::Regex(".".into(), "[bz]".into()),
+                &[KeyPattern::Literal("z".into())],
+                "regex",
+                Rank::new(0, 1),
+            )
+            .unwrap();
+        resolver.regex("a", "b", "ignore regex".into(), Rank::new(0, 2));
+        resolver
+            .except(
+                &Matcher::Section("a".into()),
+                &[
+                    KeyPattern::Literal("b".into()),
+                    KeyPattern::Regex("[cd]".into()),
+                ],
+                "section",
+                Rank::new(0, 3),
+            )
+            .unwrap();
+        // Lower ranked, but processed later
+        resolver
+            .except(&Matcher::Section("a".into()), &[], "low", Rank::new(-1, 4))
+            .unwrap();
+        // A regex added before shadows the regex with exceptions
+        resolver
+            .except(
+                &Matcher::Regex("a".into(), "b".into()),
+                &[KeyPattern::Literal("c".into())],
+                "shadowed",
+                Rank::new(0, 5),
+            )
+            .unwrap();
+        assert_eq!(
+            resolver.finish().unwrap(),
+            vec![
+                ("a".to_string(), "b".to_string(), "regex"),
+                ("a".to_string(), "b".to_string(), "low"),
+                ("a".to_string(), "c".to_string(), "low"),
+                ("a".to_string(), "d".to_string(), "low"),
+                ("a".to_string(), "e".to_string(), "low"),
+                ("a".to_string(), "e".to_string(), "section"),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_errors() {
+        let state = IniState::default();
+        let states = [&state];
+        let mut resolver = Resolver::new(&states, None);
+        let rank = Rank::new(0, 0);
+        let matcher = Matcher::Literal("a".into(), "b".into());
+        let err = resolver.value(&matcher, "(", rank).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid value regex \"(\"");
+        let err = resolver.except(&matcher, &[], (), rank).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "except can't be used with an exact key match (\"a\" \"b\")"
+        );
+        let value = Matcher::Value(Box::new(Matcher::Regex("a".into(), "b".into())), "c".into());
+        let err = resolver.except(&value, &[], (), rank).unwrap_err();
+        assert_eq!(err.to_string(), "Can't combine value regex and except");
+    }
+
+    #[test]
+    fn check_unknown() {
+        let system =
+            IniState::new("top=1\n[a]\nb=1\nc=2\nd=3\n[e]\nf=4\ng=5\n[h]\ni=6\n[j]\n[k]\nl=7\n");
+        let source = IniState::new("[a]\nb=1\n");
+        let states = [&system, &source];
+        let mut resolver = Resolver::<()>::new(&states, None);
+        resolver.literal("a", "c", Rank::new(0, 0));
+        resolver.regex("e", "g", "ignore regex \"e\" \"g\"".into(), Rank::new(0, 1));
+        resolver.section("k", Rank::new(0, 2));
+        assert_eq!(
+            resolver.unknown(&system, &source),
+            Unknown {
+                keys: keys(&[(OUTSIDE_SECTION, "top"), ("a", "d")]),
+                sections: vec![
+                    UnknownSection {
+                        name: "e".into(),
+                        keys: vec!["f".into()],
+                        untouched: false,
+                    },
+                    UnknownSection {
+                        name: "h".into(),
+                        keys: vec!["i".into()],
+                        untouched: true,
+                    },
+                    UnknownSection {
+                        name: "j".into(),
+                        keys: vec![],
+                        untouched: true,
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn check_overlaps() {
+        let state = IniState::new("[a]\nb=1\nc=2\n[d]\nb=3\n");
+        let states = [&state];
+        let overlaps = |policy, priority| {
+            let mut resolver = Resolver::<()>::new(&states, policy);
+            resolver.regex(
+                ".",
+                "b",
+                "ignore regex \".\" \"b\"".into(),
+                Rank::new(priority, 0),
+            );
+            resolver.regex("a", ".", "remove regex \"a\" \".\"".into(), Rank::new(0, 1));
+            resolver.literal("d", "b", Rank::new(0, 2));
+            resolver.overlaps(&resolver.regex_set().unwrap(), &HashSet::new())
+        };
+        assert_eq!(
+            overlaps(None, 0),
+            vec![(
+                log::Level::Warn,
+                "Overlapping rules for a/b: using `ignore regex \".\" \"b\"`, not \
+                 `remove regex \"a\" \".\"`. Use priority=N or match-policy to choose, or \
+                 no-warn-multiple-key-matches if this is intentional"
+                    .into()
+            )]
+        );
+        assert_eq!(
+            overlaps(None, 2),
+            vec![(
+                log::Level::Info,
+                "Overlapping rules for a/b: using `ignore regex \".\" \"b\" priority=2`, not \
+                 `remove regex \"a\" \".\"`"
+                    .into()
+            )]
+        );
+        assert_eq!(overlaps(Some(MatchPolicy::First), 0)[0].0, log::Level::Info);
+    }
+}
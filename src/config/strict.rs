@@ -0,0 +1,109 @@
+//! Detection of rules that don't match anything (`strict`).
+//!
+//! Rules easily go stale when an application renames its keys, this finds
+//! them so they can be pruned.
+use super::parser::Directive;
+use super::parser::Matcher;
+use super::resolve::KeyMatcher;
+use crate::ini_state::IniState;
+
+/// Describe all rules that match nothing in any of the `states`.
+///
+/// Only rules acting on existing lines are checked: `set` and `default` are
+/// meant to add keys, and the add directives only apply to the file being
+/// added. For `value regex` and `except` only the section and key names are
+/// considered.
+pub(super) fn unmatched<'a>(
+    directives: impl IntoIterator<Item = &'a Directive>,
+    states: &[&IniState],
+) -> anyhow::Result<Vec<String>> {
+    let mut result = vec![];
+    for directive in directives {
+        let Some((verb, matcher)) = checked_rule(directive) else {
+            continue;
+        };
+        if !matches_any(matcher, states)? {
+            result.push(format!("{verb} {matcher}"));
+        }
+    }
+    Ok(result)
+}
+
+/// The verb and matcher of a rule to check
+fn checked_rule(directive: &Directive) -> Option<(&'static str, &Matcher)> {
+    match directive {
+        Directive::Ignore(matcher) => Some(("ignore", matcher)),
+        Directive::Transform(matcher, _, _) => Some(("transform", matcher)),
+        Directive::Remove(matcher) => Some(("remove", matcher)),
+        Directive::Priority(_, directive) => checked_rule(directive),
+        _ => None,
+    }
+}
+
+/// Check if a matcher matches a section or key in any of the states
+fn matches_any(matcher: &Matcher, states: &[&IniState]) -> anyhow::Result<bool> {
+    match matcher {
+        Matcher::Value(matcher, _) | Matcher::Except(matcher, _) => matches_any(matcher, states),
+        Matcher::Section(_) | Matcher::SectionRegex(_) => {
+            let names = KeyMatcher::new(matcher)?;
+            Ok(states
+                .iter()
+                .flat_map(|state| state.sections())
+                .any(|section| names.is_match(section, "")))
+        }
+        Matcher::Literal(_, _) | Matcher::Regex(_, _) => {
+            let names = KeyMatcher::new(matcher)?;
+            Ok(states
+                .iter()
+                .flat_map(|state| state.keys())
+                .any(|(section, key)| names.is_match(section, key)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    #[test]
+    fn check_unmatched() {
+        let system = IniState::new("[a]\nb=1\n[empty]\n");
+        let source = IniState::new("[c]\nd=2\n");
+        let states = [&system, &source];
+        let literal = |s: &str, k: &str| Matcher::Literal(s.into(), k.into());
+        let regex = |s: &str, k: &str| Matcher::Regex(s.into(), k.into());
+        let directives = vec![
+            Directive::Ignore(literal("a", "b")),
+            Directive::Ignore(literal("a", "old")),
+            Directive::Remove(regex("c", "d.*")),
+            Directive::Priority(1, Box::new(Directive::Remove(regex("c", "e.*")))),
+            Directive::Transform(literal("c", "d"), "keyring".into(), HashMap::new()),
+            Directive::Ignore(Matcher::Section("empty".into())),
+            Directive::Ignore(Matcher::SectionRegex("^x".into())),
+            Directive::Ignore(Matcher::Value(Box::new(literal("a", "b")), "^x".into())),
+            Directive::Ignore(Matcher::Except(
+                Box::new(Matcher::Section("gone".into())),
+                vec![],
+            )),
+            // Not checked
+            Directive::Set {
+                section: "new".into(),
+                key: "key".into(),
+                value: "value".into(),
+                separator: None,
+            },
+            Directive::AddRemove(literal("x", "y")),
+        ];
+        assert_eq!(
+            unmatched(&directives, &states).unwrap(),
+            vec![
+                "ignore \"a\" \"old\"",
+                "remove regex \"c\" \"e.*\"",
+                "ignore section regex \"^x\"",
+                "ignore section \"gone\"",
+            ]
+        );
+    }
+}
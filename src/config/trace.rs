@@ -0,0 +1,274 @@
+//! Recording of which rule each action came from, for `--explain`.
+//!
+//! ini-merge doesn't tell us which action applied to a line, so the actions
+//! are recorded as they are added to the builder, and matched the same way
+//! ini-merge does it afterwards.
+use super::cache;
+use super::diagnostics::Origin;
+use super::parser::Directive;
+use ini_merge::actions::ActionsBuilderError;
+use ini_merge::mutations::Action;
+use ini_merge::mutations::Mutations;
+use ini_merge::mutations::MutationsBuilder;
+use ini_merge::mutations::SectionAction;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// What an action does to the lines it applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Effect {
+    /// Keep the line from the system state
+    Ignore,
+    /// Remove the line
+    Remove,
+    /// Set the line to a specific value
+    Set,
+    /// Apply the named transform
+    Transform(String),
+}
+
+/// The action that applies to a line, and the rule it came from
+#[derive(Debug)]
+pub(crate) struct Decision<'a> {
+    pub(crate) effect: &'a Effect,
+    /// Where the rule was written
+    pub(crate) origin: &'a Origin,
+}
+
+/// An action, with the index of the rule it came from
+type Entry = (usize, Effect);
+
+/// Regex actions, in the order they were added. Merging only needs the
+/// trace for some checks, so the regexes are compiled on first use.
+#[derive(Debug, Default)]
+struct RegexEntries {
+    entries: Vec<(String, Entry)>,
+    compiled: OnceLock<Vec<Option<Regex>>>,
+}
+
+impl RegexEntries {
+    fn push(&mut self, pattern: String, entry: Entry) {
+        self.entries.push((pattern, entry));
+    }
+
+    /// The first entry with a regex matching `haystack`
+    fn find(&self, haystack: &str) -> Option<&Entry> {
+        let compiled = self.compiled.get_or_init(|| {
+            // The regexes are known to be valid at this point
+            self.entries
+                .iter()
+                .map(|(pattern, _)| cache::regex(pattern).ok())
+                .collect()
+        });
+        compiled
+            .iter()
+            .zip(&self.entries)
+            .find(|(re, _)| re.as_ref().is_some_and(|re| re.is_match(haystack)))
+            .map(|(_, (_, entry))| entry)
+    }
+}
+
+/// The recorded actions, see [`TracingBuilder`]
+#[derive(Debug, Default)]
+pub(crate) struct Trace {
+    rules: Vec<Origin>,
+    section_literals: HashMap<String, Entry>,
+    section_regexes: RegexEntries,
+    literals: HashMap<(String, String), Entry>,
+    regexes: RegexEntries,
+}
+
+impl Trace {
+    /// Find the section action that applies to a section
+    pub(crate) fn section(&self, section: &str) -> Option<Decision<'_>> {
+        self.section_literals
+            .get(section)
+            .or_else(|| self.section_regexes.find(section))
+            .map(|entry| self.decision(entry))
+    }
+
+    /// Find the action that applies to a key, in the same order as ini-merge:
+    /// section actions, literal actions, then the first matching regex.
+    pub(crate) fn key(&self, section: &str, key: &str) -> Option<Decision<'_>> {
+        if let Some(decision) = self.section(section) {
+            return Some(decision);
+        }
+        let entry = format!("{section}\0{key}");
+        self.literals
+            .get(&(section.to_owned(), key.to_owned()))
+            .or_else(|| self.regexes.find(&entry))
+            .map(|entry| self.decision(entry))
+    }
+
+    /// Keys added by `set` directives (that aren't overridden by a section
+    /// action), in no particular order
+    pub(crate) fn set_keys(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.literals
+            .iter()
+            .filter(|(_, (_, effect))| *effect == Effect::Set)
+            .map(|((section, key), _)| (section.as_str(), key.as_str()))
+            .filter(|(section, _)| self.section(section).is_none())
+    }
+
+    fn decision<'a>(&'a self, (rule, effect): &'a Entry) -> Decision<'a> {
+        Decision {
+            effect,
+            origin: &self.rules[*rule],
+        }
+    }
+}
+
+/// Wrapper around [`MutationsBuilder`] that records the rule each action
+/// comes from.
+///
+/// Call [`TracingBuilder::rule`] before adding the actions for a rule.
+#[derive(Debug, Default)]
+pub(super) struct TracingBuilder {
+    builder: MutationsBuilder,
+    trace: Trace,
+    /// Index of the current rule
+    rule: usize,
+    /// Names of the transforms of the rules (for rules that are transforms)
+    transforms: Vec<Option<String>>,
+}
+
+impl TracingBuilder {
+    pub(super) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start adding actions for a rule, returning an index that can be used
+    /// to select it again later (see [`TracingBuilder::select`]).
+    pub(super) fn rule(&mut self, origin: Origin, directive: &Directive) -> usize {
+        self.trace.rules.push(origin);
+        self.transforms.push(match directive {
+            Directive::Transform(_, transform, _) => Some(transform.clone()),
+            _ => None,
+        });
+        self.rule = self.trace.rules.len() - 1;
+        self.rule
+    }
+
+    /// Continue adding actions for a rule started earlier
+    pub(super) const fn select(&mut self, rule: usize) {
+        self.rule = rule;
+    }
+
+    pub(super) fn warn_on_multiple_matches(&mut self, warn: bool) {
+        self.builder.warn_on_multiple_matches(warn);
+    }
+
+    pub(super) fn add_section_literal_action(&mut self, section: String, action: SectionAction) {
+        self.trace
+            .section_literals
+            .insert(section.clone(), (self.rule, section_effect(action)));
+        self.builder.add_section_literal_action(section, action);
+    }
+
+    pub(super) fn add_section_regex_action(&mut self, section: String, action: SectionAction) {
+        self.trace
+            .section_regexes
+            .push(section.clone(), (self.rule, section_effect(action)));
+        self.builder.add_section_regex_action(section, action);
+    }
+
+    pub(super) fn add_literal_action(&mut self, section: String, key: &str, action: Action) {
+        let effect = self.effect(&action);
+        self.trace
+            .literals
+            .insert((section.clone(), key.to_owned()), (self.rule, effect));
+        self.builder.add_literal_action(section, key, action);
+    }
+
+    pub(super) fn add_regex_action(&mut self, section: &str, key: &str, action: Action) {
+        let effect = self.effect(&action);
+        self.trace
+            .regexes
+            .push(format!("(?:{section})\0(?:{key})"), (self.rule, effect));
+        self.builder.add_regex_action(section, key, action);
+    }
+
+    pub(super) fn add_setter(
+        &mut self,
+        section: String,
+        key: String,
+        value: &str,
+        separator: &str,
+    ) {
+        self.trace
+            .literals
+            .insert((section.clone(), key.clone()), (self.rule, Effect::Set));
+        self.builder.add_setter(section, key, value, separator);
+    }
+
+    /// Build the mutations, and the trace of where they came from
+    pub(super) fn build(self) -> Result<(Mutations, Trace), ActionsBuilderError> {
+        Ok((self.builder.build()?, self.trace))
+    }
+
+    fn effect(&self, action: &Action) -> Effect {
+        match action {
+            Action::Ignore => Effect::Ignore,
+            Action::Delete => Effect::Remove,
+            _ => Effect::Transform(
+                self.transforms
+                    .get(self.rule)
+                    .cloned()
+                    .flatten()
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+const fn section_effect(action: SectionAction) -> Effect {
+    match action {
+        SectionAction::Ignore => Effect::Ignore,
+        _ => Effect::Remove,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parser::Matcher;
+    use pretty_assertions::assert_eq;
+
+    fn origin(line: usize) -> Origin {
+        Origin {
+            line,
+            ..Origin::default()
+        }
+    }
+
+    #[test]
+    fn check_trace() {
+        let mut builder = TracingBuilder::new();
+        let ignore = Directive::Ignore(Matcher::Section("a".into()));
+        builder.rule(origin(1), &ignore);
+        builder.add_section_regex_action("^a".into(), SectionAction::Ignore);
+        builder.rule(origin(2), &ignore);
+        builder.add_regex_action("b", "c.*", Action::Delete);
+        let first = builder.rule(origin(3), &ignore);
+        builder.add_regex_action("b", "cd", Action::Ignore);
+        builder.rule(origin(4), &ignore);
+        builder.add_setter("b".into(), "cd".into(), "x", "=");
+        builder.select(first);
+        builder.add_literal_action("b".into(), "ce", Action::Ignore);
+        let (_, trace) = builder.build().unwrap();
+
+        let line = |section, key| {
+            trace
+                .key(section, key)
+                .map(|decision| (decision.effect.clone(), decision.origin.line))
+        };
+        assert_eq!(line("ab", "x"), Some((Effect::Ignore, 1)));
+        assert_eq!(line("b", "cx"), Some((Effect::Remove, 2)));
+        assert_eq!(line("b", "cd"), Some((Effect::Set, 4)));
+        assert_eq!(line("b", "ce"), Some((Effect::Ignore, 3)));
+        assert_eq!(line("b", "x"), None);
+        assert!(trace.section("b").is_none());
+        assert_eq!(trace.set_keys().collect::<Vec<_>>(), vec![("b", "cd")]);
+    }
+}
@@ -0,0 +1,291 @@
+//! Key level diff between the system state and the merged result (`--diff`).
+
+use anstyle::AnsiColor;
+use anstyle::Style;
+use ini_merge::OUTSIDE_SECTION;
+use ini_roundtrip::Item;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// A property line in a file
+#[derive(Debug)]
+struct Property {
+    key: String,
+    value: Option<String>,
+    raw: String,
+}
+
+/// The properties of a section
+#[derive(Debug)]
+struct Section {
+    name: String,
+    /// The section header, `None` for the keys before the first section
+    header: Option<String>,
+    properties: Vec<Property>,
+}
+
+/// How a section differs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SectionStatus {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// A changed line
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Change {
+    Added(String),
+    Removed(String),
+}
+
+/// The changes to a section
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct SectionDiff {
+    /// The section header (`None` for the keys before the first section)
+    header: Option<String>,
+    status: SectionStatus,
+    changes: Vec<Change>,
+}
+
+/// Compare the system state with the merged result, key by key.
+///
+/// Sections are listed in the order of the merged result, followed by
+/// removed sections. Within a section changed and added keys are listed in
+/// the order of the merged result, followed by removed keys. Comments,
+/// blank lines and formatting only changes are not included.
+pub(crate) fn diff(system: &str, merged: &str) -> Vec<SectionDiff> {
+    let system = parse(system);
+    let merged = parse(merged);
+    let mut result = vec![];
+    for section in &merged {
+        let Some(old) = system.iter().find(|s| s.name == section.name) else {
+            result.push(SectionDiff {
+                header: section.header.clone(),
+                status: SectionStatus::Added,
+                changes: section
+                    .properties
+                    .iter()
+                    .map(|p| Change::Added(p.raw.clone()))
+                    .collect(),
+            });
+            continue;
+        };
+        let mut changes = vec![];
+        for property in &section.properties {
+            match old.properties.iter().find(|p| p.key == property.key) {
+                Some(old) if old.value == property.value => (),
+                Some(old) => {
+                    changes.push(Change::Removed(old.raw.clone()));
+                    changes.push(Change::Added(property.raw.clone()));
+                }
+                None => changes.push(Change::Added(property.raw.clone())),
+            }
+        }
+        changes.extend(
+            old.properties
+                .iter()
+                .filter(|old| !section.properties.iter().any(|p| p.key == old.key))
+                .map(|old| Change::Removed(old.raw.clone())),
+        );
+        if !changes.is_empty() {
+            result.push(SectionDiff {
+                header: section.header.clone(),
+                status: SectionStatus::Changed,
+                changes,
+            });
+        }
+    }
+    for section in system
+        .iter()
+        .filter(|section| !merged.iter().any(|s| s.name == section.name))
+    {
+        if section.header.is_none() && section.properties.is_empty() {
+            continue;
+        }
+        result.push(SectionDiff {
+            header: section.header.clone(),
+            status: SectionStatus::Removed,
+            changes: section
+                .properties
+                .iter()
+                .map(|p| Change::Removed(p.raw.clone()))
+                .collect(),
+        });
+    }
+    result
+}
+
+/// The section and key of every property that differs between two files,
+/// including added and removed ones.
+pub(crate) fn changed_keys(old: &str, new: &str) -> BTreeSet<(String, String)> {
+    let (old, new) = (key_values(old), key_values(new));
+    old.keys()
+        .chain(new.keys())
+        .filter(|key| old.get(*key) != new.get(*key))
+        .cloned()
+        .collect()
+}
+
+/// The value of every property by section and key. For duplicate keys the
+/// last one wins.
+pub(crate) fn key_values(contents: &str) -> HashMap<(String, String), Option<String>> {
+    parse(contents)
+        .into_iter()
+        .flat_map(|section| {
+            section
+                .properties
+                .into_iter()
+                .map(move |p| ((section.name.clone(), p.key), p.value))
+        })
+        .collect()
+}
+
+/// Split a file into sections. Properties outside of any section are put in
+/// the first section, which may be empty.
+fn parse(contents: &str) -> Vec<Section> {
+    let mut sections = vec![Section {
+        name: OUTSIDE_SECTION.into(),
+        header: None,
+        properties: vec![],
+    }];
+    for item in ini_roundtrip::Parser::new(contents) {
+        match item {
+            Item::Section { name, raw } => sections.push(Section {
+                name: name.into(),
+                header: Some(raw.into()),
+                properties: vec![],
+            }),
+            Item::Property { key, val, raw } => {
+                if let Some(section) = sections.last_mut() {
+                    section.properties.push(Property {
+                        key: key.into(),
+                        value: val.map(str::to_owned),
+                        raw: raw.into(),
+                    });
+                }
+            }
+            Item::Error(_) | Item::SectionEnd | Item::Comment { .. } | Item::Blank { .. } => (),
+        }
+    }
+    sections
+}
+
+/// Write the diff, with colours if `color` is set.
+pub(crate) fn write(
+    diff: &[SectionDiff],
+    out: &mut impl Write,
+    color: bool,
+) -> std::io::Result<()> {
+    let style = |color_code: AnsiColor| {
+        if color {
+            Style::new().fg_color(Some(color_code.into()))
+        } else {
+            Style::new()
+        }
+    };
+    let (added, removed, section) = (
+        style(AnsiColor::Green),
+        style(AnsiColor::Red),
+        style(AnsiColor::Cyan),
+    );
+    for section_diff in diff {
+        let header = section_diff
+            .header
+            .as_deref()
+            .unwrap_or("(before first section)");
+        match section_diff.status {
+            SectionStatus::Added => writeln!(out, "{added}+{header}{added:#}")?,
+            SectionStatus::Removed => writeln!(out, "{removed}-{header}{removed:#}")?,
+            SectionStatus::Changed => writeln!(out, "{section}@@ {header} @@{section:#}")?,
+        }
+        for change in &section_diff.changes {
+            match change {
+                Change::Added(line) => writeln!(out, "{added}+{line}{added:#}")?,
+                Change::Removed(line) => writeln!(out, "{removed}-{line}{removed:#}")?,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    fn check(system: &str, merged: &str) -> String {
+        let mut out = vec![];
+        write(&diff(system, merged), &mut out, false).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn check_diff() {
+        let system = indoc! {"
+            top=1
+            [same]
+            a=1
+            [changed]
+            # Comment
+            a=1
+            b = 1
+            c=1
+            [removed]
+            a=1
+        "};
+        let merged = indoc! {"
+            top=1
+            [same]
+            a=1
+            [changed]
+            a=2
+            b=1
+            d=1
+            [added]
+            a=1
+        "};
+        assert_eq!(
+            check(system, merged),
+            indoc! {"
+                @@ [changed] @@
+                -a=1
+                +a=2
+                +d=1
+                -c=1
+                +[added]
+                +a=1
+                -[removed]
+                -a=1
+            "}
+        );
+        assert_eq!(check(system, system), "");
+        assert_eq!(
+            check("", "top=1\n"),
+            "@@ (before first section) @@\n+top=1\n"
+        );
+    }
+
+    #[test]
+    fn check_changed_keys() {
+        let changed = changed_keys("top=1\n[a]\nb=1\nc = 1\nd=1\n", "[a]\nb=2\nc=1\ne=1\n");
+        let expected = [(OUTSIDE_SECTION, "top"), ("a", "b"), ("a", "d"), ("a", "e")];
+        assert_eq!(
+            changed,
+            expected
+                .iter()
+                .map(|(s, k)| ((*s).to_owned(), (*k).to_owned()))
+                .collect()
+        );
+    }
+
+    #[test]
+    fn check_color() {
+        let mut out = vec![];
+        write(&diff("[a]\n", "[a]\nb=1\n"), &mut out, true).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\u{1b}[32m+b=1\u{1b}[0m"));
+    }
+}
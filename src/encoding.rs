@@ -0,0 +1,263 @@
+//! Support for INI files that aren't UTF-8 (the `encoding` directive) or
+//! that use CRLF line endings (the `line-endings` directive).
+//!
+//! Files are decoded to UTF-8 for merging and filtering, and the result is
+//! encoded back the same way the file was, including any byte order mark and
+//! the style of line endings.
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// A supported text encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// ISO-8859-1
+    Latin1,
+}
+
+/// A style of line endings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum LineEnding {
+    /// Unix style
+    Lf,
+    /// Windows style
+    CrLf,
+}
+
+impl LineEnding {
+    /// Find the style of the first line ending in `text`, if there is one
+    pub(crate) fn detect(text: &str) -> Option<Self> {
+        let idx = text.find('\n')?;
+        Some(if text[..idx].ends_with('\r') {
+            Self::CrLf
+        } else {
+            Self::Lf
+        })
+    }
+}
+
+/// How a file was encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Format {
+    pub(crate) encoding: Encoding,
+    /// The file started with a byte order mark
+    pub(crate) bom: bool,
+    pub(crate) line_ending: LineEnding,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self {
+            encoding: Encoding::Utf8,
+            bom: false,
+            line_ending: LineEnding::Lf,
+        }
+    }
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+const UTF16LE_BOM: &[u8] = b"\xFF\xFE";
+const UTF16BE_BOM: &[u8] = b"\xFE\xFF";
+
+impl Encoding {
+    const fn bom(self) -> &'static [u8] {
+        match self {
+            Self::Utf8 => UTF8_BOM,
+            Self::Utf16Le => UTF16LE_BOM,
+            Self::Utf16Be => UTF16BE_BOM,
+            Self::Latin1 => b"",
+        }
+    }
+
+    /// Find the encoding from a byte order mark at the start of `bytes`
+    fn detect(bytes: &[u8]) -> Option<Self> {
+        [Self::Utf8, Self::Utf16Le, Self::Utf16Be]
+            .into_iter()
+            .find(|encoding| bytes.starts_with(encoding.bom()))
+    }
+}
+
+/// Decode a file, using the `declared` encoding or else detecting it from
+/// the byte order mark (defaulting to UTF-8). The line endings are detected
+/// from the first line (defaulting to LF).
+pub(crate) fn decode(bytes: &[u8], declared: Option<Encoding>) -> anyhow::Result<(String, Format)> {
+    let detected = Encoding::detect(bytes);
+    let encoding = declared.or(detected).unwrap_or(Encoding::Utf8);
+    let bom = detected == Some(encoding);
+    let data = if bom {
+        &bytes[encoding.bom().len()..]
+    } else {
+        bytes
+    };
+    let text = match encoding {
+        Encoding::Utf8 => String::from_utf8(data.to_vec()).map_err(|_| {
+            anyhow!("Not valid UTF-8, use the encoding directive for other encodings")
+        })?,
+        Encoding::Utf16Le | Encoding::Utf16Be => {
+            if data.len() % 2 != 0 {
+                return Err(anyhow!("Not valid UTF-16: odd number of bytes"));
+            }
+            let units = data.chunks_exact(2).map(|pair| {
+                let pair = [pair[0], pair[1]];
+                if encoding == Encoding::Utf16Le {
+                    u16::from_le_bytes(pair)
+                } else {
+                    u16::from_be_bytes(pair)
+                }
+            });
+            char::decode_utf16(units)
+                .collect::<Result<String, _>>()
+                .map_err(|err| anyhow!("Not valid UTF-16: {err}"))?
+        }
+        Encoding::Latin1 => data.iter().copied().map(char::from).collect(),
+    };
+    let line_ending = LineEnding::detect(&text).unwrap_or(LineEnding::Lf);
+    Ok((
+        text,
+        Format {
+            encoding,
+            bom,
+            line_ending,
+        },
+    ))
+}
+
+/// Encode text the way it was decoded
+pub(crate) fn encode(text: &str, format: Format) -> anyhow::Result<Vec<u8>> {
+    let crlf;
+    let text = match format.line_ending {
+        LineEnding::Lf => text,
+        LineEnding::CrLf => {
+            crlf = text.replace("\r\n", "\n").replace('\n', "\r\n");
+            &crlf
+        }
+    };
+    let mut result = vec![];
+    if format.bom {
+        result.extend_from_slice(format.encoding.bom());
+    }
+    match format.encoding {
+        Encoding::Utf8 => result.extend_from_slice(text.as_bytes()),
+        Encoding::Utf16Le => result.extend(text.encode_utf16().flat_map(u16::to_le_bytes)),
+        Encoding::Utf16Be => result.extend(text.encode_utf16().flat_map(u16::to_be_bytes)),
+        Encoding::Latin1 => {
+            for c in text.chars() {
+                let byte = u8::try_from(u32::from(c))
+                    .map_err(|_| anyhow!("Character {c:?} can't be represented in Latin-1"))?;
+                result.push(byte);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Make sure a file ends with a newline, in the encoding and style of line
+/// endings it already uses (as far as they can be detected).
+pub(crate) fn ensure_newline(bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let Ok((mut text, format)) = decode(&bytes, None) else {
+        // Not something we can decode, just make sure it ends with a newline
+        let mut bytes = bytes;
+        if !bytes.ends_with(b"\n") {
+            bytes.push(b'\n');
+        }
+        return Ok(bytes);
+    };
+    if text.ends_with('\n') {
+        return Ok(bytes);
+    }
+    text.push('\n');
+    encode(&text, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn roundtrip(bytes: &[u8], declared: Option<Encoding>) -> (String, Format) {
+        let (text, format) = decode(bytes, declared).unwrap();
+        assert_eq!(encode(&text, format).unwrap(), bytes);
+        (text, format)
+    }
+
+    fn format(encoding: Encoding, bom: bool) -> Format {
+        Format {
+            encoding,
+            bom,
+            line_ending: LineEnding::Lf,
+        }
+    }
+
+    #[test]
+    fn check_detect() {
+        assert_eq!(
+            roundtrip(b"[a]\nb=\xC3\xA5\n", None),
+            ("[a]\nb=å\n".into(), format(Encoding::Utf8, false))
+        );
+        assert_eq!(
+            roundtrip(b"\xEF\xBB\xBFb=1\n", None),
+            ("b=1\n".into(), format(Encoding::Utf8, true))
+        );
+        assert_eq!(
+            roundtrip(b"\xFF\xFEb\0=\0\xE5\0\n\0", None),
+            ("b=å\n".into(), format(Encoding::Utf16Le, true))
+        );
+        assert_eq!(
+            roundtrip(b"\xFE\xFF\0b\0=\0\xE5\0\n", None),
+            ("b=å\n".into(), format(Encoding::Utf16Be, true))
+        );
+    }
+
+    #[test]
+    fn check_declared() {
+        assert_eq!(
+            roundtrip(b"b=\xE5\n", Some(Encoding::Latin1)),
+            ("b=å\n".into(), format(Encoding::Latin1, false))
+        );
+        assert_eq!(
+            roundtrip(b"b\0=\0\xE5\0", Some(Encoding::Utf16Le)),
+            ("b=å".into(), format(Encoding::Utf16Le, false))
+        );
+        assert_eq!(
+            roundtrip(b"\xFF\xFEb\0", Some(Encoding::Utf16Le)),
+            ("b".into(), format(Encoding::Utf16Le, true))
+        );
+    }
+
+    #[test]
+    fn check_errors() {
+        assert!(decode(b"b=\xE5\n", None).is_err());
+        assert!(decode(b"\xFF\xFEb", None).is_err());
+        assert!(decode(b"\xFF\xFE\x00\xD8", None).is_err());
+        assert!(encode("€", format(Encoding::Latin1, false)).is_err());
+    }
+
+    #[test]
+    fn check_line_endings() {
+        assert_eq!(LineEnding::detect("a"), None);
+        assert_eq!(LineEnding::detect("a\nb\r\n"), Some(LineEnding::Lf));
+        assert_eq!(LineEnding::detect("a\r\nb\n"), Some(LineEnding::CrLf));
+        let (text, crlf) = roundtrip(b"\xFF\xFEa\0\r\0\n\0", None);
+        assert_eq!(crlf.line_ending, LineEnding::CrLf);
+        assert_eq!(encode(&text, crlf).unwrap(), b"\xFF\xFEa\0\r\0\n\0");
+        assert_eq!(
+            encode("a\nb\n", crlf).unwrap(),
+            b"\xFF\xFEa\0\r\0\n\0b\0\r\0\n\0"
+        );
+    }
+
+    #[test]
+    fn check_ensure_newline() {
+        let check = |bytes: &[u8]| ensure_newline(bytes.to_vec()).unwrap();
+        assert_eq!(check(b"a"), b"a\n");
+        assert_eq!(check(b"a\n"), b"a\n");
+        assert_eq!(check(b"a\r\nb"), b"a\r\nb\r\n");
+        assert_eq!(check(b"a\xE5"), b"a\xE5\n");
+        assert_eq!(check(b"\xFF\xFEa\0"), b"\xFF\xFEa\0\n\0");
+        assert_eq!(check(b"\xFE\xFF\0a"), b"\xFE\xFF\0a\0\n");
+    }
+}
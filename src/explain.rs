@@ -0,0 +1,355 @@
+//! Annotation of merged output with where each line came from (`--explain`).
+
+use crate::config::Effect;
+use crate::config::Origin;
+use crate::config::Trace;
+use ini_merge::OUTSIDE_SECTION;
+use ini_roundtrip::Item;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Write;
+
+/// Where a line in the output came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, strum::IntoStaticStr)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum LineOrigin {
+    /// Kept from the system state
+    System,
+    /// Taken from the source file
+    Source,
+    /// Set by a `set` or `default` directive
+    Set,
+    /// Produced by a transform
+    Transform,
+    /// In the system state but not in the output
+    Removed,
+    /// From the source file and not present in the system state before
+    Inserted,
+}
+
+/// The directive that decided a line
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct RuleLocation {
+    file: String,
+    line: usize,
+    directive: String,
+}
+
+impl From<&Origin> for RuleLocation {
+    fn from(origin: &Origin) -> Self {
+        Self {
+            file: origin.file.to_string(),
+            line: origin.line,
+            directive: origin.text.clone(),
+        }
+    }
+}
+
+/// A line of output (or a removed line) with its origin
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct ExplainedLine {
+    line: String,
+    origin: LineOrigin,
+    /// The section, `None` before the first section header
+    section: Option<String>,
+    key: Option<String>,
+    /// Name of the transform, for transformed lines
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transform: Option<String>,
+    rule: Option<RuleLocation>,
+}
+
+/// Explain the `merged` output, given the system state it was merged from
+/// (with renames applied) and the trace from building the mutations.
+///
+/// Lines in the system state that are missing from the output are included
+/// as removed, after the rest of their section (or at the end if the whole
+/// section was removed).
+pub(crate) fn explain(merged: &[String], system: &str, trace: &Trace) -> Vec<ExplainedLine> {
+    let mut system_sections = HashSet::new();
+    let mut system_lines = vec![];
+    for (section, key, raw) in entries(system) {
+        system_sections.insert(section.clone());
+        system_lines.push((section, key, raw));
+    }
+
+    let mut result = vec![];
+    let mut output_keys = HashSet::new();
+    let mut output_sections = HashSet::new();
+    let merged: String = merged.iter().map(|line| format!("{line}\n")).collect();
+    for (section, key, raw) in entries(&merged) {
+        let in_system = system_sections.contains(&section);
+        let decision = match &key {
+            Some(key) => trace.key(&section, key),
+            None => trace.section(&section),
+        };
+        let (origin, transform) = match decision.as_ref().map(|d| d.effect) {
+            Some(Effect::Ignore) => (LineOrigin::System, None),
+            Some(Effect::Set) => (LineOrigin::Set, None),
+            Some(Effect::Transform(name)) => (LineOrigin::Transform, Some(name.clone())),
+            Some(Effect::Remove) | None => {
+                let is_new = match &key {
+                    Some(key) => !system_lines
+                        .iter()
+                        .any(|(s, k, _)| *s == section && k.as_ref() == Some(key)),
+                    None => !in_system,
+                };
+                if is_new {
+                    (LineOrigin::Inserted, None)
+                } else {
+                    (LineOrigin::Source, None)
+                }
+            }
+        };
+        if let Some(key) = &key {
+            output_keys.insert((section.clone(), key.clone()));
+        }
+        output_sections.insert(section.clone());
+        result.push(ExplainedLine {
+            line: raw,
+            origin,
+            section: section_name(section),
+            key,
+            transform,
+            rule: decision.map(|d| d.origin.into()),
+        });
+    }
+
+    // Removed lines go after the last non-blank line of their section
+    let mut removed: HashMap<Option<usize>, Vec<ExplainedLine>> = HashMap::new();
+    for (section, key, raw) in system_lines {
+        let is_removed = match &key {
+            Some(key) => !output_keys.contains(&(section.clone(), key.clone())),
+            None => !output_sections.contains(&section),
+        };
+        if !is_removed || (key.is_none() && section == OUTSIDE_SECTION) {
+            continue;
+        }
+        let section = section_name(section);
+        let position = result
+            .iter()
+            .rposition(|line| line.section == section && !line.line.trim().is_empty());
+        let rule = match &key {
+            Some(key) => trace.key(section.as_deref().unwrap_or(OUTSIDE_SECTION), key),
+            None => trace.section(section.as_deref().unwrap_or(OUTSIDE_SECTION)),
+        }
+        .filter(|decision| *decision.effect == Effect::Remove)
+        .map(|decision| decision.origin.into());
+        removed.entry(position).or_default().push(ExplainedLine {
+            line: raw,
+            origin: LineOrigin::Removed,
+            section,
+            key,
+            transform: None,
+            rule,
+        });
+    }
+
+    let mut explained = vec![];
+    for (idx, line) in result.into_iter().enumerate() {
+        explained.push(line);
+        explained.extend(removed.remove(&Some(idx)).unwrap_or_default());
+    }
+    explained.extend(removed.remove(&None).unwrap_or_default());
+    explained
+}
+
+/// Section headers and properties in a file, as `(section, key, raw line)`.
+/// The key is `None` for headers. Comments and blank lines are included with
+/// the section they are in, and no key.
+fn entries(contents: &str) -> Vec<(String, Option<String>, String)> {
+    let mut section = OUTSIDE_SECTION.to_owned();
+    let mut result = vec![];
+    for item in ini_roundtrip::Parser::new(contents) {
+        match item {
+            Item::SectionEnd => (),
+            Item::Section { name, raw } => {
+                section = name.into();
+                result.push((section.clone(), None, raw.into()));
+            }
+            Item::Property { key, raw, .. } => {
+                result.push((section.clone(), Some(key.into()), raw.into()));
+            }
+            Item::Error(raw) | Item::Comment { raw } | Item::Blank { raw } => {
+                result.push((section.clone(), None, raw.into()));
+            }
+        }
+    }
+    result
+}
+
+fn section_name(section: String) -> Option<String> {
+    (section != OUTSIDE_SECTION).then_some(section)
+}
+
+/// Write the explanation as text: each line prefixed with its origin, and
+/// followed by the directive that decided it.
+pub(crate) fn write_text(lines: &[ExplainedLine], out: &mut impl Write) -> std::io::Result<()> {
+    let label = |line: &ExplainedLine| {
+        let origin: &str = line.origin.into();
+        match &line.transform {
+            Some(transform) => format!("{origin}:{transform}"),
+            None => origin.to_owned(),
+        }
+    };
+    let width = lines
+        .iter()
+        .map(|line| label(line).len())
+        .max()
+        .unwrap_or(0);
+    for line in lines {
+        let text = format!("{:<width$} | {}", label(line), line.line);
+        match &line.rule {
+            Some(rule) => writeln!(
+                out,
+                "{text}  # {}:{}: {}",
+                rule.file, rule.line, rule.directive
+            )?,
+            None if line.origin == LineOrigin::Removed && line.key.is_some() => {
+                writeln!(out, "{text}  # not in the source file")?;
+            }
+            None => writeln!(out, "{}", text.trim_end())?,
+        }
+    }
+    Ok(())
+}
+
+/// Write the explanation as JSON
+pub(crate) fn write_json(lines: &[ExplainedLine], out: &mut impl Write) -> anyhow::Result<()> {
+    serde_json::to_writer_pretty(&mut *out, lines)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_for_merge;
+    use camino::Utf8Path;
+    use indoc::indoc;
+    use ini_merge::merge::merge_ini;
+    use pretty_assertions::assert_eq;
+
+    fn run(config: &str, system: &str, source: &str) -> Vec<ExplainedLine> {
+        let path = Utf8Path::new("modify_test");
+        let config = parse_for_merge(config, path, path).unwrap();
+        let (mutations, trace) = config.mutations.build_traced(system, source).unwrap();
+        let merged = merge_ini(&mut system.as_bytes(), &mut source.as_bytes(), &mutations).unwrap();
+        let merged = crate::reorder::reorder(&merged, system, source);
+        explain(&merged, system, &trace)
+    }
+
+    #[test]
+    fn check_explain() {
+        let config = indoc! {r#"
+            source "x"
+            ignore "a" "state"
+            set "a" "forced" "1"
+            transform "a" "sorted" unsorted-list separator=","
+            remove "a" "gone"
+        "#};
+        let system = indoc! {"
+            [a]
+            state=1
+            gone=1
+            old=1
+            sorted=b,a
+            [b]
+            x=1
+        "};
+        let source = indoc! {"
+            [a]
+            state=2
+            sorted=a,b
+            new=2
+        "};
+        let explained = run(config, system, source);
+        let summary: Vec<_> = explained
+            .iter()
+            .map(|line| {
+                (
+                    line.line.as_str(),
+                    line.origin,
+                    line.rule.as_ref().map(|rule| rule.line),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("[a]", LineOrigin::Source, None),
+                ("state=1", LineOrigin::System, Some(2)),
+                ("sorted=b,a", LineOrigin::Transform, Some(4)),
+                ("new=2", LineOrigin::Inserted, None),
+                ("forced = 1", LineOrigin::Set, Some(3)),
+                ("gone=1", LineOrigin::Removed, Some(5)),
+                ("old=1", LineOrigin::Removed, None),
+                ("[b]", LineOrigin::Removed, None),
+                ("x=1", LineOrigin::Removed, None),
+            ]
+        );
+        assert_eq!(explained[2].transform.as_deref(), Some("unsorted-list"));
+        assert_eq!(
+            explained[1].rule,
+            Some(RuleLocation {
+                file: "modify_test".into(),
+                line: 2,
+                directive: r#"ignore "a" "state""#.into(),
+            })
+        );
+    }
+
+    #[test]
+    fn check_write() {
+        let lines = vec![
+            ExplainedLine {
+                line: "k=1".into(),
+                origin: LineOrigin::System,
+                section: Some("a".into()),
+                key: Some("k".into()),
+                transform: None,
+                rule: Some(RuleLocation {
+                    file: "f".into(),
+                    line: 3,
+                    directive: r#"ignore "a" "k""#.into(),
+                }),
+            },
+            ExplainedLine {
+                line: "old=1".into(),
+                origin: LineOrigin::Removed,
+                section: Some("a".into()),
+                key: Some("old".into()),
+                transform: None,
+                rule: None,
+            },
+        ];
+        let mut out = vec![];
+        write_text(&lines, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc! {r#"
+                system  | k=1  # f:3: ignore "a" "k"
+                removed | old=1  # not in the source file
+            "#}
+        );
+
+        let mut out = vec![];
+        write_json(&lines[1..], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc! {r#"
+                [
+                  {
+                    "line": "old=1",
+                    "origin": "removed",
+                    "section": "a",
+                    "key": "old",
+                    "rule": null
+                  }
+                ]
+            "#}
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Summary of the contents of an INI file (the system or the source state)
+
+use ini_merge::OUTSIDE_SECTION;
+use ini_roundtrip::Item;
+use std::collections::HashMap;
+
+/// The keys (and their values) in an INI file, used to resolve directives
+/// that depend on the actual contents of the files being merged.
+#[derive(Debug, Default)]
+pub(crate) struct IniState {
+    /// All section and key pairs with their values, in file order
+    entries: Vec<(String, String, Option<String>)>,
+    /// Index into `entries` (first occurrence)
+    index: HashMap<(String, String), usize>,
+    /// All section names (including empty sections), in file order
+    sections: Vec<String>,
+}
+
+impl IniState {
+    /// Scan the contents of an INI file
+    pub(crate) fn new(contents: &str) -> Self {
+        let mut result = Self::default();
+        let mut cur_section = OUTSIDE_SECTION;
+        for item in ini_roundtrip::Parser::new(contents) {
+            match item {
+                Item::Section { name, .. } => {
+                    if !result.sections.iter().any(|section| section == name) {
+                        result.sections.push(name.to_owned());
+                    }
+                    cur_section = name;
+                }
+                Item::Property { key, val, .. } => {
+                    let entry = (cur_section.to_owned(), key.to_owned());
+                    if !result.index.contains_key(&entry) {
+                        result.index.insert(entry.clone(), result.entries.len());
+                        result
+                            .entries
+                            .push((entry.0, entry.1, val.map(str::to_owned)));
+                    }
+                }
+                Item::Error(_) | Item::SectionEnd | Item::Comment { .. } | Item::Blank { .. } => {}
+            }
+        }
+        result
+    }
+
+    /// Check if a key exists
+    pub(crate) fn has_key(&self, section: &str, key: &str) -> bool {
+        self.index
+            .contains_key(&(section.to_owned(), key.to_owned()))
+    }
+
+    /// Get the value of a key. The outer option is `None` if the key doesn't
+    /// exist, the inner if the key has no value (no `=`).
+    pub(crate) fn value(&self, section: &str, key: &str) -> Option<Option<&str>> {
+        self.index
+            .get(&(section.to_owned(), key.to_owned()))
+            .map(|idx| self.entries[*idx].2.as_deref())
+    }
+
+    /// Iterate over all section names, in file order
+    pub(crate) fn sections(&self) -> impl Iterator<Item = &str> {
+        self.sections.iter().map(String::as_str)
+    }
+
+    /// Iterate over all section and key pairs, in file order
+    pub(crate) fn keys(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(section, key, _)| (section.as_str(), key.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_has_key() {
+        let state = IniState::new("top=1\n[a]\nb=c\nd\n; e=f\n");
+        assert!(state.has_key(OUTSIDE_SECTION, "top"));
+        assert!(state.has_key("a", "b"));
+        assert!(state.has_key("a", "d"));
+        assert!(!state.has_key("a", "e"));
+        assert!(!state.has_key("a", "top"));
+        assert!(!IniState::default().has_key("a", "b"));
+    }
+
+    #[test]
+    fn check_values() {
+        let state = IniState::new("[a]\nb = c\nd\nb=second\n[e]\nb=f\n");
+        assert_eq!(state.value("a", "b"), Some(Some("c")));
+        assert_eq!(state.value("a", "d"), Some(None));
+        assert_eq!(state.value("a", "x"), None);
+        assert_eq!(
+            state.keys().collect::<Vec<_>>(),
+            vec![("a", "b"), ("a", "d"), ("e", "b")]
+        );
+    }
+
+    #[test]
+    fn check_sections() {
+        let state = IniState::new("top=1\n[a]\n[b]\nc=d\n[a]\ne=f\n");
+        assert_eq!(state.sections().collect::<Vec<_>>(), vec!["a", "b"]);
+    }
+}
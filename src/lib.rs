@@ -1,25 +1,38 @@
 //! This is not a stable API, and is to be used internally by the binary and
 //! the integration tests only.
 
+use crate::encoding::LineEnding;
 use crate::utils::CHEZMOI_AUTO_SOURCE_VERSION;
 use crate::utils::RealChezmoi;
 pub use add::Style;
 use anyhow::Context;
 pub use arguments::ChmmArgs;
 pub use arguments::parse_args;
+use camino::Utf8Path;
 use indoc::printdoc;
 use ini_merge::merge::merge_ini;
-use std::fs::File;
+use std::borrow::Cow;
 use std::io::Read;
 use std::io::Write;
 
 mod add;
 mod arguments;
+mod batch;
+mod check;
 mod config;
+mod diff;
 mod doctor;
+mod encoding;
+mod explain;
+mod ini_state;
+mod local_changes;
+mod rename;
+mod reorder;
+mod standalone;
 mod transforms;
 mod update;
 mod utils;
+mod validate;
 
 /// Main function, amenable to integration tests.
 ///
@@ -41,24 +54,82 @@ where
     FS: FnOnce() -> WS,
 {
     match opts {
-        ChmmArgs::Process(file_name) => {
-            let buf = std::fs::read_to_string(&file_name)
-                .with_context(|| format!("Failed to load {file_name}"))?;
-            let c = config::parse_for_merge(&buf)
-                .with_context(|| format!("Failed to parse {file_name}"))?;
-
-            let mut stdin = stdin();
-            let src_path = c
-                .source_path(&file_name)
-                .context("Failed to get source path")?;
-            let mut src_file = File::open(src_path.as_std_path())
-                .with_context(|| format!("Failed to open source file at: {src_path}"))?;
-            let merged = merge_ini(&mut stdin, &mut src_file, &c.mutations)?;
+        ChmmArgs::Process {
+            strict,
+            diff,
+            no_cache,
+            system: system_path,
+            file: file_name,
+        } => {
+            let cache = if no_cache {
+                None
+            } else {
+                config::DirectiveCache::from_env()
+            };
+            let (c, system) =
+                load_script(&file_name, system_path.as_deref(), cache.as_ref(), stdin)?;
+            let base_state = local_changes::BaseState::from_env();
+            let options = MergeOptions {
+                strict,
+                diff,
+                base_state: base_state.as_ref(),
+            };
+            let output = merge_file(c, &file_name, &system, options)?;
+            stdout().write_all(&output)?;
+        }
+        ChmmArgs::Explain {
+            _a,
+            json,
+            file: file_name,
+        } => {
+            let MergeInput {
+                config: mut c,
+                system,
+                source,
+                ..
+            } = load_for_merge(&file_name, None, None, stdin)?;
+            c.mutations.report_overlaps();
+            let (merged, trace) = merge(c.mutations, &file_name, &system, &source)?;
+            let explained = explain::explain(&merged, &system, &trace);
             let mut stdout = stdout();
-            for line in merged {
-                writeln!(stdout, "{line}")?;
+            if json {
+                explain::write_json(&explained, &mut stdout)?;
+            } else {
+                explain::write_text(&explained, &mut stdout)?;
             }
         }
+        ChmmArgs::ProcessBatch { manifest } => {
+            let contents = if manifest == "-" {
+                let mut contents = String::new();
+                stdin()
+                    .read_to_string(&mut contents)
+                    .context("Failed to read manifest from stdin")?;
+                contents
+            } else {
+                std::fs::read_to_string(&manifest)
+                    .with_context(|| format!("Failed to read manifest {manifest}"))?
+            };
+            let mut stdout = status();
+            let cache = config::DirectiveCache::from_env();
+            batch::process_batch(&contents, cache.as_ref(), &mut stdout)?;
+        }
+        ChmmArgs::Merge {
+            rules,
+            source,
+            system,
+            output,
+            in_place,
+        } => {
+            let destination = match (output, in_place) {
+                (Some(_), true) => {
+                    return Err(anyhow::anyhow!("--output and --in-place can't be combined"));
+                }
+                (Some(path), false) => standalone::Destination::File(path),
+                (None, true) => standalone::Destination::File(system.clone()),
+                (None, false) => standalone::Destination::Stdout,
+            };
+            standalone::merge(&rules, &source, &system, destination, stdout)?;
+        }
         ChmmArgs::Add {
             _a,
             recursive,
@@ -107,6 +178,10 @@ where
             );
             std::process::exit(1);
         }
+        ChmmArgs::Check { _a, files } => {
+            let mut stdout = status();
+            check::check(&RealChezmoi::default(), &files, &mut stdout)?;
+        }
         ChmmArgs::Doctor { _a } => doctor::doctor()?,
         ChmmArgs::HelpSyntax { _a } => help_syntax(),
         ChmmArgs::HelpTransforms { _a } => transforms::Transform::help(),
@@ -133,6 +208,235 @@ where
     Ok(())
 }
 
+/// A loaded modify script, with the files to merge
+struct MergeInput {
+    config: config::Config<config::MergeRules>,
+    /// The system state (with renames applied)
+    system: String,
+    source: String,
+    /// How to encode the result
+    format: encoding::Format,
+}
+
+/// Load a modify script, the system state (from `system_path` or stdin) and
+/// the source file, as needed for merging. The parsed script is cached in
+/// `cache` if given.
+fn load_for_merge<R: Read>(
+    file_name: &Utf8Path,
+    system_path: Option<&Utf8Path>,
+    cache: Option<&config::DirectiveCache>,
+    stdin: impl FnOnce() -> R,
+) -> anyhow::Result<MergeInput> {
+    let (c, system) = load_script(file_name, system_path, cache, stdin)?;
+    MergeInput::load(c, &system, file_name)
+}
+
+/// Load and parse a modify script, and read the system state (from
+/// `system_path` or stdin).
+fn load_script<R: Read>(
+    file_name: &Utf8Path,
+    system_path: Option<&Utf8Path>,
+    cache: Option<&config::DirectiveCache>,
+    stdin: impl FnOnce() -> R,
+) -> anyhow::Result<(config::Config<config::MergeRules>, Vec<u8>)> {
+    let buf = std::fs::read_to_string(file_name)
+        .with_context(|| format!("Failed to load {file_name}"))?;
+    let include_root = config::include_root_from_env(file_name);
+    let system = match system_path {
+        Some(path) => std::fs::read(path)
+            .with_context(|| format!("Failed to read system state from {path}"))?,
+        None => {
+            let mut system = vec![];
+            stdin()
+                .read_to_end(&mut system)
+                .context("Failed to read system state from stdin")?;
+            system
+        }
+    };
+    let c = match cache {
+        Some(cache) => config::parse_for_merge_cached(&buf, file_name, &include_root, cache),
+        None => config::parse_for_merge(&buf, file_name, &include_root),
+    }
+    .with_context(|| format!("Failed to parse {file_name}"))?;
+    Ok((c, system))
+}
+
+/// How to merge a file, beyond what the rules say
+#[derive(Debug, Default, Clone, Copy)]
+struct MergeOptions<'a> {
+    /// Fail if rules don't match anything (`--strict`)
+    strict: bool,
+    /// Output a diff instead of the merged file (`--diff`)
+    diff: bool,
+    /// Where to check for (and record) local changes to the target file, see
+    /// `conflict-policy`. Only done when run by chezmoi.
+    base_state: Option<&'a local_changes::BaseState>,
+}
+
+/// Merge a loaded modify script (or rules file) with the system state and
+/// the source file, returning what to output: the merged file, or the diff if
+/// `options.diff` is set.
+///
+/// If merging fails, the `on-error` policy decides what to output instead
+/// (except for diffs, which should show what went wrong).
+fn merge_file(
+    c: config::Config<config::MergeRules>,
+    file_name: &Utf8Path,
+    system: &[u8],
+    options: MergeOptions<'_>,
+) -> anyhow::Result<Vec<u8>> {
+    let on_error = c.mutations.on_error();
+    let src_path = c.source_path(file_name).ok().map(Cow::into_owned);
+    match try_merge_file(c, file_name, system, options) {
+        Ok(output) => Ok(output),
+        Err(err) if options.diff => Err(err),
+        Err(err) => fallback(on_error, file_name, system, src_path.as_deref(), err),
+    }
+}
+
+/// Merge a file, without applying the `on-error` policy
+fn try_merge_file(
+    c: config::Config<config::MergeRules>,
+    file_name: &Utf8Path,
+    system: &[u8],
+    options: MergeOptions<'_>,
+) -> anyhow::Result<Vec<u8>> {
+    let MergeInput {
+        config: c,
+        system,
+        source,
+        format,
+    } = MergeInput::load(c, system, file_name)?;
+    c.mutations
+        .check_unmatched(options.strict, file_name, &system, &source)?;
+    let conflict_policy = c.mutations.conflict_policy();
+    let (merged, trace) = merge(c.mutations, file_name, &system, &source)?;
+    // Every line ends with a newline, so nothing at all if there are no lines
+    let output: String = merged.iter().map(|line| format!("{line}\n")).collect();
+    if let Some(base) = options
+        .base_state
+        .map(local_changes::BaseState::load)
+        .transpose()?
+        .flatten()
+    {
+        let conflicts = local_changes::conflicts(&base, &system, &output, &trace);
+        local_changes::report(conflict_policy, file_name, &conflicts)?;
+    }
+    if options.diff {
+        let color =
+            anstream::AutoStream::choice(&std::io::stdout()) != anstream::ColorChoice::Never;
+        let mut out = vec![];
+        diff::write(&diff::diff(&system, &output), &mut out, color)?;
+        return Ok(out);
+    }
+    let encoded = encoding::encode(&output, format)?;
+    if let Some(base_state) = options.base_state
+        && local_changes::is_applying()
+    {
+        // Record the file as the next run will read it
+        let (written, _) = encoding::decode(&encoded, c.encoding)?;
+        base_state.store(&c.renames.apply(&written))?;
+    }
+    Ok(encoded)
+}
+
+/// What to output when merging failed, according to the `on-error` policy
+fn fallback(
+    policy: config::OnError,
+    file_name: &Utf8Path,
+    system: &[u8],
+    src_path: Option<&Utf8Path>,
+    err: anyhow::Error,
+) -> anyhow::Result<Vec<u8>> {
+    match policy {
+        config::OnError::Fail => Err(err),
+        config::OnError::Passthrough => {
+            log::error!(
+                "{file_name}: Leaving the target file unchanged (on-error passthrough): {err:#}"
+            );
+            Ok(system.to_vec())
+        }
+        config::OnError::Source => match src_path.and_then(|path| std::fs::read(path).ok()) {
+            Some(source) => {
+                log::error!(
+                    "{file_name}: Writing the source file unchanged (on-error source): {err:#}"
+                );
+                Ok(source)
+            }
+            None => Err(err.context("The source file can't be read either (on-error source)")),
+        },
+    }
+}
+
+impl MergeInput {
+    /// Read the source file, and decode it and the system state
+    fn load(
+        c: config::Config<config::MergeRules>,
+        system: &[u8],
+        file_name: &Utf8Path,
+    ) -> anyhow::Result<Self> {
+        let src_path = c
+            .source_path(file_name)
+            .context("Failed to get source path")?
+            .into_owned();
+        let source = std::fs::read(src_path.as_std_path())
+            .with_context(|| format!("Failed to open source file at: {src_path}"))?;
+        Self::decode(c, system, &source, &src_path)
+    }
+
+    /// Decode the system state and source file, and apply the renames
+    fn decode(
+        c: config::Config<config::MergeRules>,
+        system: &[u8],
+        source: &[u8],
+        src_path: &Utf8Path,
+    ) -> anyhow::Result<Self> {
+        let (system, system_format) =
+            encoding::decode(system, c.encoding).context("Failed to decode system state")?;
+        let system = c.renames.apply(&system).into_owned();
+        let (source, source_format) = encoding::decode(source, c.encoding)
+            .with_context(|| format!("Failed to decode source file at: {src_path}"))?;
+        // A new file is written like the source file
+        let mut format = if system.is_empty() {
+            source_format
+        } else {
+            system_format
+        };
+        format.line_ending = c
+            .line_endings
+            .or_else(|| LineEnding::detect(&system))
+            .or_else(|| LineEnding::detect(&source))
+            .unwrap_or(LineEnding::Lf);
+        Ok(Self {
+            config: c,
+            format,
+            system,
+            source,
+        })
+    }
+}
+
+/// Merge the system state with the source file, returning the merged lines
+/// and a trace of which rule decided what. The result is validated unless
+/// disabled with `validate off`.
+fn merge(
+    rules: config::MergeRules,
+    file_name: &Utf8Path,
+    system: &str,
+    source: &str,
+) -> anyhow::Result<(Vec<String>, config::Trace)> {
+    let validate = rules.validate();
+    let (mutations, trace) = rules
+        .build_traced(system, source)
+        .with_context(|| format!("Failed to parse {file_name}"))?;
+    let merged = merge_ini(&mut system.as_bytes(), &mut source.as_bytes(), &mutations)?;
+    let merged = reorder::reorder(&merged, system, source);
+    if validate {
+        validate::check(file_name, system, source, &merged, &trace)?;
+    }
+    Ok((merged, trace))
+}
+
 /// Print help for the overall syntax of the configuration language.
 fn help_syntax() {
     printdoc! {r#"
@@ -148,6 +452,15 @@ fn help_syntax() {
 
     The file consists of directives, one per line. Comments are supported by
     prefixing a line with #. Comments are only supported at the start of lines.
+    Directives (and comments) may be indented with spaces or tabs.
+
+    Strings can be written in three ways:
+    * "normal strings" support the escapes \\, \", \n, \t, \xNN (ASCII only)
+      and \u{{NNNN}}.
+    * r"raw strings" take the content verbatim, up until the next ".
+    * 'single quoted strings' are verbatim too, up until the next '.
+
+    Raw strings are useful for regexes, e.g. r"File\d+" instead of "File\\d+".
 
     Directives
     ==========
@@ -189,6 +502,15 @@ fn help_syntax() {
     Finally, ignored lines will not be added back when using --add or
     --smart-add, in order to reduce git diffs.
 
+    The section and regex forms can exempt keys, which are then merged as
+    normal (source state wins, unless another directive applies):
+
+    ignore section "MainWindow" except "ToolBarsMovable"
+    ignore regex "General" "Recent.*" except "RecentMax" except regex "RecentDir.*"
+
+    These are resolved against the keys currently in the files, and keep the
+    priority of the form they are used with (see actions.md).
+
     set
     ---
     Set an entry to a specific value. This is primarily useful together with
@@ -207,6 +529,17 @@ fn help_syntax() {
       it is likely the line will get formatted weirdly (which will often be
       changed by the program the INI file belongs to).
 
+    default
+    -------
+    Like set, but only when the key doesn't exist in the target file yet. This
+    seeds a value on a new computer, but leaves whatever it is changed to
+    afterwards alone. The same variants as for set are supported:
+
+    default "section" "key" "value"
+    default "section" "key" "value" separator="="
+
+    Once the key exists, default behaves exactly like ignore.
+
     remove
     ------
     Unconditionally remove everything matching the directive. This is primarily
@@ -215,6 +548,7 @@ fn help_syntax() {
     supported:
 
     remove section "my-section"
+    remove section regex "^MySection.*"
     remove "my-section" "my-key"
     remove regex "section.*regex" "key regex.*"
 
@@ -271,8 +605,241 @@ fn help_syntax() {
     This directive quitens warnings on multiple regular expressions matching the
     same section+key. While the warning is generally useful, sometimes you might
     actually "know what you are doing" and want to suppress it.
+
+    The warning is only given when nothing but the order in the file decides
+    which rule wins, see priority and match-policy below. It is given by
+    --check, --explain and --add, not when merging (to keep that fast).
+
+    priority
+    --------
+    ignore, remove, transform, set, default, add:remove and add:hide take an
+    optional priority at the end (default 0, may be negative):
+
+    remove regex "General" "Recent.*" priority=10
+
+    When several rules of the same kind match a key, the one with the highest
+    priority wins. Section matches still win over key matches, and exact
+    literal matches over regex matches.
+
+    match-policy
+    ------------
+    Choose which regex rule wins when several with the same priority match:
+
+    match-policy first
+    match-policy last
+    match-policy most-specific
+
+    first (the default) and last go by the order in the file, most-specific
+    picks the regex with the most literal characters. With an explicit policy,
+    overlapping regexes are not warned about.
+
+    strict
+    ------
+    Report ignore, remove and transform rules that match nothing in either the
+    target or the source file (e.g. because an application renamed a key):
+
+    strict
+    strict warn
+    strict error
+
+    strict warn logs a warning per rule, strict (or strict error) fails with an
+    error listing them. The --strict flag has the same effect as strict error.
+    set and default are not checked, as they are meant to add keys.
+
+    unknown-keys & unknown-sections
+    -------------------------------
+    Control what happens to keys and sections that are in the target file but
+    not in the source file, and that no other directive matches:
+
+    unknown-keys keep
+    unknown-sections warn
+
+    Supported are remove (the default, delete them), keep (keep them, like
+    ignore) and warn (delete them, but log a warning). unknown-keys applies to
+    keys in sections that exist in the source file, unknown-sections to whole
+    sections that don't.
+
+    conflict-policy
+    ---------------
+    The merged output is recorded when applying. Keys that were changed
+    locally since then, and that merging would overwrite, are reported:
+
+    conflict-policy warn
+    conflict-policy error
+    conflict-policy overwrite
+
+    warn (the default) logs a warning and overwrites the changes, error fails
+    without writing the file and overwrite does so silently. Ignored keys are
+    never reported.
+
+    encoding
+    --------
+    Files are read as UTF-8 unless they start with a byte order mark for UTF-8
+    or UTF-16. Other encodings without byte order mark can be specified:
+
+    encoding latin-1
+
+    Supported are auto (the default), utf-8, utf-16le, utf-16be and latin-1.
+    The result is written in the same encoding as the target file, with a byte
+    order mark if it had one.
+
+    line-endings
+    ------------
+    The result is written with the line endings of the target file (LF or
+    CRLF, as found on its first line), or of the source file if the target
+    file is empty. To always use a specific style:
+
+    line-endings crlf
+
+    Supported are auto (the default), lf and crlf.
+
+    on-error
+    --------
+    What to do if merging fails (e.g. the source file is missing, or a
+    strict or conflict-policy check fails):
+
+    on-error passthrough
+
+    Supported are fail (the default: exit with an error), passthrough (output
+    the target file unmodified) and source (output the source file as is).
+    The error is logged to stderr either way. Errors in the modify script
+    itself (including invalid regexes and unknown transforms) always fail.
+    Transforms that fail at runtime (such as a locked keyring) only log an
+    error and don't trigger on-error.
+
+    validate
+    --------
+    The merged result is checked before it is output: it must not contain
+    malformed lines, duplicate section headers or keys outside of sections
+    that weren't in the target or source file, and all keys from set
+    directives must be present. To turn this off:
+
+    validate off
+
+    include
+    -------
+    Include directives from another file. This is useful to share common
+    rules between several modify scripts:
+
+    include "shared/kde-common.rules"
+
+    Relative paths are resolved relative to the chezmoi source directory.
+    Included files use the same syntax as modify scripts and may in turn
+    include other files. They are not processed by chezmoi's template engine.
+
+    rename
+    ------
+    Rename sections or keys in the target file, to follow an application that
+    renamed them between versions:
+
+    rename section "old-section" to "new-section"
+    rename "old-section" "old-key" to "new-section" "new-key"
+
+    Renames are applied to the target file before anything else, so other
+    directives should use the new names. When adding, only the new names are
+    written to the source state. If the new name already exists, the line with
+    the old name is dropped.
+
+    if
+    --
+    Apply directives only on some computers, without making the script a
+    template. Supported conditions are:
+
+    if hostname "laptop" {{
+    if os "linux" {{
+    if env "VAR" == "value" {{
+    if env "VAR" != "value" {{
+    if env "VAR" {{
+    if exists "/some/path" {{
+
+    Blocks are closed with }} on a line of its own, and may have an else branch
+    ("}} else {{"). Blocks can be nested.
+
+    When adding, conditions are not evaluated. Directives inside blocks are
+    skipped by default, use "add:conditions all-branches" to use the
+    directives from every branch instead.
+
+    section blocks
+    --------------
+    Directives on keys in the same section can be grouped in a block, to avoid
+    repeating the section name:
+
+    section "Colors:View" {{ ignore "BackgroundAlternate"; transform "Foo" kde-shortcut }}
+    section "General" {{
+        ignore "fontSize"
+        ignore regex "recent.*"
+    }}
+    section regex "Colors:.*" {{
+        add:remove "BackgroundAlternate"
+    }}
+
+    Statements are separated by ; or newlines. Supported are ignore, remove,
+    transform, set, default, add:remove and add:hide, taking only a key (or
+    regex "key regex") instead of a section and a key. set and default are not
+    supported in section regex blocks.
+
+    value regex
+    -----------
+    ignore, remove, transform, add:remove and add:hide on keys can be limited
+    to keys whose current value matches a regex:
+
+    ignore regex "Recent Files" "File.*" value regex "^/home/"
+    remove "General" "timestamp" value regex r"^\d+$"
+
+    The value is taken from the target file if the key exists there, otherwise
+    from the source file. Matching keys are handled like exact literal matches,
+    so they take priority over other regex matches for the same key.
     "#,
     CHEZMOI_AUTO_SOURCE_VERSION,
     r#"source "{{ .chezmoi.sourceDir }}/{{ .chezmoi.sourceFile | trimSuffix ".tmpl" | replace "modify_" "" }}.src.ini""#,
     CHEZMOI_AUTO_SOURCE_VERSION};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use camino::Utf8PathBuf;
+    use pretty_assertions::assert_eq;
+
+    /// Merge with a script in a temporary directory, recording the base state
+    fn merge_recorded(script: &str, system: &[u8]) -> (anyhow::Result<Vec<u8>>, Option<String>) {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        let script_path = root.join("modify_test");
+        std::fs::write(root.join("test.src.ini"), "[a]\nb=1\n").unwrap();
+        let script = format!("source \"{root}/test.src.ini\"\n{script}");
+        let c = config::parse_for_merge(&script, &script_path, &root).unwrap();
+        let base_state = local_changes::BaseState::new(root.join("base"));
+        let options = MergeOptions {
+            base_state: Some(&base_state),
+            ..MergeOptions::default()
+        };
+        let result = merge_file(c, &script_path, system, options);
+        (result, std::fs::read_to_string(root.join("base")).ok())
+    }
+
+    #[test]
+    fn check_base_state_written() {
+        let (result, base) = merge_recorded("line-endings crlf\n", b"[a]\r\nb=2\r\n");
+        assert_eq!(result.unwrap(), b"[a]\r\nb=1\r\n");
+        assert_eq!(
+            base.unwrap(),
+            local_changes::KeyHashes::new("[a]\nb=1\n").to_string()
+        );
+    }
+
+    #[test]
+    fn check_base_state_not_written() {
+        // Can't be encoded
+        let (result, base) =
+            merge_recorded("encoding latin-1\nset \"a\" \"c\" \"\u{20ac}\"\n", b"");
+        assert!(result.is_err());
+        assert_eq!(base, None);
+
+        // The target file is output instead
+        let script = "encoding latin-1\non-error passthrough\nset \"a\" \"c\" \"\u{20ac}\"\n";
+        let (result, base) = merge_recorded(script, b"[a]\nb=2\n");
+        assert_eq!(result.unwrap(), b"[a]\nb=2\n");
+        assert_eq!(base, None);
+    }
+}
@@ -0,0 +1,307 @@
+//! Detection of local changes to the target file since the last apply
+//! (`conflict-policy`).
+//!
+//! Modify scripts bypass chezmoi's check for whether the target file changed
+//! since it was last written. To make up for that, the merged output is
+//! recorded for each modify script, and compared to the target file the next
+//! time the script runs.
+//!
+//! Only hashes of the keys and values are recorded, as values may be secrets
+//! (e.g. from `set` or the keyring).
+
+use crate::config::ConflictPolicy;
+use crate::config::Effect;
+use crate::config::Trace;
+use crate::diff::changed_keys;
+use crate::diff::key_values;
+use crate::utils;
+use anyhow::Context;
+use anyhow::anyhow;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use ini_merge::OUTSIDE_SECTION;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io::ErrorKind;
+
+/// First line of a stored base state, to detect incompatible formats
+const HEADER: &str = "chezmoi_modify_manager base state v1";
+
+/// The recorded output from the last apply, for one modify script
+#[derive(Debug)]
+pub(crate) struct BaseState {
+    path: Utf8PathBuf,
+}
+
+impl BaseState {
+    /// The base state of the modify script chezmoi is running, if running
+    /// under chezmoi.
+    pub(crate) fn from_env() -> Option<Self> {
+        let source_file = std::env::var("CHEZMOI_SOURCE_FILE").ok()?;
+        Some(Self {
+            path: state_dir()?.join(file_name(&source_file)),
+        })
+    }
+
+    /// A base state stored in a specific file
+    #[cfg(test)]
+    pub(crate) fn new(path: Utf8PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Load the recorded output, if there is any (in a format this version
+    /// understands)
+    pub(crate) fn load(&self) -> anyhow::Result<Option<KeyHashes>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(contents) => Ok(KeyHashes::parse(&contents)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => {
+                Err(err).with_context(|| format!("Failed to read base state {}", self.path))
+            }
+        }
+    }
+
+    /// Record the merged output
+    pub(crate) fn store(&self, merged: &str) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            utils::create_private_dir(dir)?;
+        }
+        utils::write_private(&self.path, KeyHashes::new(merged).to_string().as_bytes())
+            .with_context(|| format!("Failed to write base state {}", self.path))
+    }
+}
+
+/// Hashes of every property in a file: the hash of the section and key maps
+/// to the hash of the section, key and value.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct KeyHashes(HashMap<String, String>);
+
+impl KeyHashes {
+    /// Hash the properties of an INI file
+    pub(crate) fn new(contents: &str) -> Self {
+        Self(
+            key_values(contents)
+                .into_iter()
+                .map(|((section, key), value)| {
+                    let name = format!("{section}\0{key}");
+                    let value_hash = match value {
+                        Some(value) => utils::sha256_hex(&format!("{name}\0={value}")),
+                        None => utils::sha256_hex(&format!("{name}\0")),
+                    };
+                    (utils::sha256_hex(&name), value_hash)
+                })
+                .collect(),
+        )
+    }
+
+    /// Parse the stored format, `None` if it isn't understood
+    fn parse(contents: &str) -> Option<Self> {
+        let mut lines = contents.lines();
+        if lines.next()? != HEADER {
+            return None;
+        }
+        lines
+            .map(|line| {
+                let (name, value) = line.split_once(' ')?;
+                Some((name.to_owned(), value.to_owned()))
+            })
+            .collect::<Option<_>>()
+            .map(Self)
+    }
+
+    /// The hash of the value of a key, `None` if the key doesn't exist
+    fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.0
+            .get(&utils::sha256_hex(&format!("{section}\0{key}")))
+            .map(String::as_str)
+    }
+}
+
+impl std::fmt::Display for KeyHashes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{HEADER}")?;
+        // Sorted, so that the file only changes when the contents do
+        let mut entries: Vec<_> = self.0.iter().collect();
+        entries.sort_unstable();
+        for (name, value) in entries {
+            writeln!(f, "{name} {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Check if chezmoi is writing the target file (as opposed to e.g. showing a
+/// diff), so that the output should be recorded.
+///
+/// Older versions of chezmoi don't tell, assume they are applying.
+pub(crate) fn is_applying() -> bool {
+    std::env::var("CHEZMOI_COMMAND").map_or(true, |command| {
+        matches!(command.as_str(), "apply" | "init" | "update")
+    })
+}
+
+/// Directory to store base states in: `$XDG_STATE_HOME` (or
+/// `~/.local/state`) on Unix, `%LOCALAPPDATA%` on Windows.
+fn state_dir() -> Option<Utf8PathBuf> {
+    let base = utils::user_dir("XDG_STATE_HOME", ".local/state")?;
+    Some(base.join("chezmoi_modify_manager").join("base"))
+}
+
+/// Turn the path of a modify script (relative to the chezmoi source
+/// directory) into a single file name.
+fn file_name(source_file: &str) -> String {
+    let mut result = String::new();
+    for byte in source_file.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-' | b'_') {
+            result.push(char::from(byte));
+        } else {
+            _ = write!(result, "%{byte:02X}");
+        }
+    }
+    result
+}
+
+/// Find keys that were changed locally (compared to the `base` state) and
+/// that merging would change again. Keys that are ignored are never
+/// conflicts, as their local value is kept.
+pub(crate) fn conflicts(
+    base: &KeyHashes,
+    system: &str,
+    merged: &str,
+    trace: &Trace,
+) -> Vec<(String, String)> {
+    let current = KeyHashes::new(system);
+    changed_keys(system, merged)
+        .into_iter()
+        .filter(|(section, key)| base.get(section, key) != current.get(section, key))
+        .filter(|(section, key)| {
+            trace
+                .key(section, key)
+                .is_none_or(|decision| *decision.effect != Effect::Ignore)
+        })
+        .collect()
+}
+
+/// Report conflicts according to the `conflict-policy`
+pub(crate) fn report(
+    policy: ConflictPolicy,
+    script_path: &Utf8Path,
+    conflicts: &[(String, String)],
+) -> anyhow::Result<()> {
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    let describe = |(section, key): &(String, String)| {
+        if section == OUTSIDE_SECTION {
+            key.clone()
+        } else {
+            format!("{section}/{key}")
+        }
+    };
+    match policy {
+        ConflictPolicy::Overwrite => Ok(()),
+        ConflictPolicy::Warn => {
+            for conflict in conflicts {
+                log::warn!(
+                    "{script_path}: Overwriting local change to {} since the last apply",
+                    describe(conflict)
+                );
+            }
+            Ok(())
+        }
+        ConflictPolicy::Error => Err(anyhow!(
+            "{script_path}: Keys changed locally since the last apply would be \
+             overwritten (re-add the file, or use conflict-policy overwrite):\n{}",
+            conflicts
+                .iter()
+                .map(|conflict| format!("  {}", describe(conflict)))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_for_merge;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn check_file_name() {
+        assert_eq!(
+            file_name("private_dot_config/modify_kdeglobals"),
+            "private_dot_config%2Fmodify_kdeglobals"
+        );
+        assert_eq!(file_name("a b\\c"), "a%20b%5Cc");
+    }
+
+    #[test]
+    fn check_store() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        let state = BaseState {
+            path: root.join("base/modify_test"),
+        };
+        assert_eq!(state.load().unwrap(), None);
+        state.store("[a]\nb=secret\n").unwrap();
+        assert_eq!(
+            state.load().unwrap(),
+            Some(KeyHashes::new("[a]\nb = secret\n"))
+        );
+        let stored = std::fs::read_to_string(&state.path).unwrap();
+        assert!(!stored.contains("secret"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&state.path), 0o600);
+            assert_eq!(mode(&root.join("base")), 0o700);
+        }
+
+        // Unknown formats are ignored
+        std::fs::write(&state.path, "[a]\nb=1\n").unwrap();
+        assert_eq!(state.load().unwrap(), None);
+    }
+
+    #[test]
+    fn check_key_hashes() {
+        let hashes = KeyHashes::new("top=1\n[a]\nb=1\nc\nd=\n");
+        assert_eq!(
+            hashes.get("a", "b"),
+            KeyHashes::new("[a]\nb=1").get("a", "b")
+        );
+        assert_ne!(
+            hashes.get("a", "b"),
+            KeyHashes::new("[a]\nb=2").get("a", "b")
+        );
+        assert_ne!(hashes.get("a", "c"), hashes.get("a", "d"));
+        assert!(hashes.get(OUTSIDE_SECTION, "top").is_some());
+        assert_eq!(hashes.get("a", "e"), None);
+        assert_eq!(KeyHashes::parse(&hashes.to_string()), Some(hashes));
+    }
+
+    #[test]
+    fn check_conflicts() {
+        let path = Utf8Path::new("modify_test");
+        let config = parse_for_merge("source auto\nignore \"a\" \"kept\"", path, path).unwrap();
+        let base = "[a]\nchanged=1\nkept=1\nsame=1\nreverted=1\n";
+        let system = "[a]\nchanged=2\nkept=2\nsame=1\nreverted=2\n";
+        let source = "[a]\nchanged=1\nsame=1\nreverted=2\n";
+        let (mutations, trace) = config.mutations.build_traced(system, source).unwrap();
+        let merged =
+            ini_merge::merge::merge_ini(&mut system.as_bytes(), &mut source.as_bytes(), &mutations)
+                .unwrap();
+        let merged = merged.join("\n") + "\n";
+        let conflicts = conflicts(&KeyHashes::new(base), system, &merged, &trace);
+        assert_eq!(conflicts, vec![("a".to_owned(), "changed".to_owned())]);
+
+        assert!(report(ConflictPolicy::Warn, path, &conflicts).is_ok());
+        assert!(report(ConflictPolicy::Overwrite, path, &conflicts).is_ok());
+        let err = report(ConflictPolicy::Error, path, &conflicts).unwrap_err();
+        assert!(err.to_string().starts_with(
+            "modify_test: Keys changed locally since the last apply would be overwritten"
+        ));
+        assert!(err.to_string().ends_with(":\n  a/changed"));
+    }
+}
@@ -0,0 +1,308 @@
+//! Renaming of sections and keys in the system state.
+//!
+//! This is applied to the system (target) file before it is merged with the
+//! source state (or filtered when adding), so that the rest of the directives
+//! only ever see the new names.
+
+use anyhow::anyhow;
+use ini_merge::OUTSIDE_SECTION;
+use ini_roundtrip::Item;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// Set of renames to apply to an INI file
+#[derive(Debug, Default)]
+pub(crate) struct Renames {
+    /// Old section name to new section name
+    sections: HashMap<String, String>,
+    /// Old section and key to new section and key
+    keys: HashMap<(String, String), (String, String)>,
+}
+
+/// A section in the output, with all lines belonging to it
+#[derive(Debug)]
+struct OutputSection<'a> {
+    /// The section header, `None` for the keys before the first section
+    header: Option<Cow<'a, str>>,
+    lines: Vec<Cow<'a, str>>,
+    /// True if the section didn't exist in the input (only in renames)
+    created: bool,
+}
+
+impl Renames {
+    /// Add a rename of an entire section
+    pub(crate) fn add_section(&mut self, from: String, to: String) -> anyhow::Result<()> {
+        if self.sections.contains_key(&from) {
+            return Err(anyhow!("Section \"{from}\" is renamed multiple times"));
+        }
+        self.sections.insert(from, to);
+        Ok(())
+    }
+
+    /// Add a rename of a single key, possibly moving it to another section
+    pub(crate) fn add_key(
+        &mut self,
+        section: String,
+        key: String,
+        new_section: String,
+        new_key: String,
+    ) -> anyhow::Result<()> {
+        let from = (section, key);
+        if self.keys.contains_key(&from) {
+            return Err(anyhow!(
+                "Key \"{}\" in section \"{}\" is renamed multiple times",
+                from.1,
+                from.0
+            ));
+        }
+        self.keys.insert(from, (new_section, new_key));
+        Ok(())
+    }
+
+    /// Apply the renames to the contents of an INI file.
+    ///
+    /// Renamed sections keep their position. Keys moved to a different section
+    /// are appended to the end of that section (which is created at the end
+    /// of the file if needed). If the new name already exists in the file, the
+    /// line with the old name is dropped.
+    pub(crate) fn apply<'a>(&'a self, input: &'a str) -> Cow<'a, str> {
+        if self.sections.is_empty() && self.keys.is_empty() {
+            return Cow::Borrowed(input);
+        }
+
+        let existing = self.existing_keys(input);
+        let mut sections = vec![OutputSection {
+            header: None,
+            lines: vec![],
+            created: false,
+        }];
+        let mut index: HashMap<String, usize> = HashMap::from([(OUTSIDE_SECTION.into(), 0)]);
+        // Original and renamed name of the current section
+        let mut cur_section = (OUTSIDE_SECTION, OUTSIDE_SECTION);
+        let mut cur_idx = 0;
+        // Sections in the order they appear in the input
+        let mut order = vec![0];
+
+        for item in ini_roundtrip::Parser::new(input) {
+            match item {
+                Item::SectionEnd => (),
+                Item::Section { name, raw } => {
+                    let (new_name, header) = match self.sections.get(name) {
+                        Some(new_name) => (new_name.as_str(), Cow::Owned(format!("[{new_name}]"))),
+                        None => (name, Cow::Borrowed(raw)),
+                    };
+                    cur_idx = *index.entry(new_name.to_string()).or_insert_with(|| {
+                        sections.push(OutputSection {
+                            header: None,
+                            lines: vec![],
+                            created: true,
+                        });
+                        sections.len() - 1
+                    });
+                    let section = &mut sections[cur_idx];
+                    if section.created {
+                        section.header = Some(header);
+                        section.created = false;
+                        order.push(cur_idx);
+                    }
+                    cur_section = (name, new_name);
+                }
+                Item::Property { key, raw, .. } => {
+                    let Some((new_section, new_key)) = self.key_rename(cur_section, key) else {
+                        sections[cur_idx].lines.push(Cow::Borrowed(raw));
+                        continue;
+                    };
+                    if existing.contains(&(new_section, new_key)) {
+                        // The application already wrote the new name, prefer that
+                        continue;
+                    }
+                    let line = Cow::Owned(raw.replacen(key, new_key, 1));
+                    if new_section == cur_section.1 {
+                        sections[cur_idx].lines.push(line);
+                        continue;
+                    }
+                    // The section might appear later in the input, otherwise it
+                    // is created at the end.
+                    let idx = *index.entry(new_section.to_string()).or_insert_with(|| {
+                        sections.push(OutputSection {
+                            header: Some(Cow::Owned(format!("[{new_section}]"))),
+                            lines: vec![],
+                            created: true,
+                        });
+                        sections.len() - 1
+                    });
+                    // Keep any trailing blank lines at the end of the section
+                    let lines = &mut sections[idx].lines;
+                    let pos = lines.len()
+                        - lines
+                            .iter()
+                            .rev()
+                            .take_while(|l| l.trim().is_empty())
+                            .count();
+                    lines.insert(pos, line);
+                }
+                Item::Error(raw) | Item::Comment { raw } | Item::Blank { raw } => {
+                    sections[cur_idx].lines.push(Cow::Borrowed(raw));
+                }
+            }
+        }
+
+        let mut result = String::with_capacity(input.len());
+        let created = (0..sections.len()).filter(|idx| sections[*idx].created);
+        for idx in order.iter().copied().chain(created) {
+            let section = &sections[idx];
+            for line in section.header.iter().chain(section.lines.iter()) {
+                result.push_str(line);
+                result.push('\n');
+            }
+        }
+        Cow::Owned(result)
+    }
+
+    /// Find the rename (if any) for a key, given the original and the renamed
+    /// name of the section it is in.
+    fn key_rename(&self, section: (&str, &str), key: &str) -> Option<(&str, &str)> {
+        self.keys
+            .get(&(section.0.to_string(), key.to_string()))
+            .or_else(|| self.keys.get(&(section.1.to_string(), key.to_string())))
+            .map(|(s, k)| (s.as_str(), k.as_str()))
+    }
+
+    /// Find all keys (after section renames) that are not themselves renamed.
+    fn existing_keys<'a>(&'a self, input: &'a str) -> HashSet<(&'a str, &'a str)> {
+        let mut result = HashSet::new();
+        let mut cur_section = (OUTSIDE_SECTION, OUTSIDE_SECTION);
+        for item in ini_roundtrip::Parser::new(input) {
+            match item {
+                Item::Section { name, .. } => {
+                    let new_name = self.sections.get(name).map_or(name, String::as_str);
+                    cur_section = (name, new_name);
+                }
+                Item::Property { key, .. } if self.key_rename(cur_section, key).is_none() => {
+                    result.insert((cur_section.1, key));
+                }
+                _ => (),
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn check_no_renames() {
+        let renames = Renames::default();
+        assert!(matches!(renames.apply("[a]\nb=c\n"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn check_rename_section() {
+        let mut renames = Renames::default();
+        renames
+            .add_section("General".into(), "Appearance".into())
+            .unwrap();
+        let out = renames.apply(indoc! {"
+            top=1
+            [General]
+            fontSize=12
+            ; comment
+
+            [Other]
+            a=b
+        "});
+        assert_eq!(
+            out,
+            indoc! {"
+            top=1
+            [Appearance]
+            fontSize=12
+            ; comment
+
+            [Other]
+            a=b
+        "}
+        );
+    }
+
+    #[test]
+    fn check_rename_key() {
+        let mut renames = Renames::default();
+        renames
+            .add_key(
+                "General".into(),
+                "fontSize".into(),
+                "Appearance".into(),
+                "font_size".into(),
+            )
+            .unwrap();
+        renames
+            .add_key(
+                "General".into(),
+                "colour".into(),
+                "General".into(),
+                "color".into(),
+            )
+            .unwrap();
+        renames
+            .add_key(
+                "General".into(),
+                "theme".into(),
+                "New".into(),
+                "theme".into(),
+            )
+            .unwrap();
+        let out = renames.apply(indoc! {"
+            [Appearance]
+            dpi=96
+
+            [General]
+            fontSize = 12
+            colour=red
+            theme=dark
+        "});
+        assert_eq!(
+            out,
+            indoc! {"
+            [Appearance]
+            dpi=96
+            font_size = 12
+
+            [General]
+            color=red
+            [New]
+            theme=dark
+        "}
+        );
+    }
+
+    #[test]
+    fn check_rename_existing() {
+        let mut renames = Renames::default();
+        renames
+            .add_key("a".into(), "old".into(), "a".into(), "new".into())
+            .unwrap();
+        let out = renames.apply("[a]\nold=1\nnew=2\n");
+        assert_eq!(out, "[a]\nnew=2\n");
+    }
+
+    #[test]
+    fn check_duplicate() {
+        let mut renames = Renames::default();
+        renames.add_section("a".into(), "b".into()).unwrap();
+        assert!(renames.add_section("a".into(), "c".into()).is_err());
+        renames
+            .add_key("a".into(), "b".into(), "c".into(), "d".into())
+            .unwrap();
+        assert!(
+            renames
+                .add_key("a".into(), "b".into(), "e".into(), "f".into())
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,344 @@
+//! Reordering of the merged output to follow the source file.
+//!
+//! ini-merge appends keys that are missing from the system state to the end of
+//! their section (sorted by name), and sections missing from the system state
+//! to the end of the file. Applications that don't re-sort their settings
+//! would then produce a diff on every apply. Instead such keys are moved after
+//! their preceding neighbour in the source file, and new sections are placed
+//! in source order.
+
+use crate::ini_state::IniState;
+use ini_merge::OUTSIDE_SECTION;
+use ini_roundtrip::Item;
+use std::collections::HashSet;
+
+/// A section in the merged output
+#[derive(Debug)]
+struct Section {
+    name: String,
+    /// The section header, `None` for the keys before the first section
+    header: Option<String>,
+    lines: Vec<Line>,
+}
+
+/// A line in a section
+#[derive(Debug)]
+struct Line {
+    /// The key, if this line is a property
+    key: Option<String>,
+    raw: String,
+}
+
+impl Section {
+    /// Find the line with a key
+    fn position(&self, key: &str) -> Option<usize> {
+        self.lines
+            .iter()
+            .position(|line| line.key.as_deref() == Some(key))
+    }
+
+    fn ends_with_blank(&self) -> bool {
+        self.lines
+            .last()
+            .is_some_and(|line| line.key.is_none() && line.raw.trim().is_empty())
+    }
+}
+
+/// Reorder the merged lines to follow the order in the source file.
+///
+/// `system` must be the system state the merge was done on (i.e. with renames
+/// applied). Keys and sections that exist in the system state are never
+/// moved. Keys that aren't in the source file (from `set`) stay at the end of
+/// their section. A new section that is first in the source file is placed
+/// before the following section.
+pub(crate) fn reorder(merged: &[String], system: &str, source: &str) -> Vec<String> {
+    let system = IniState::new(system);
+    let source = IniState::new(source);
+    let system_sections: HashSet<_> = system.sections().collect();
+    let merged: String = merged.iter().map(|line| format!("{line}\n")).collect();
+    let mut sections = parse(&merged);
+
+    for section in &mut sections {
+        let is_new = section.header.is_some() && !system_sections.contains(section.name.as_str());
+        reorder_keys(section, is_new, &system, &source);
+    }
+
+    // Move new sections next to their neighbours in the source file
+    let source_sections: Vec<_> = source.sections().collect();
+    let separated = sections
+        .iter()
+        .take(sections.len().saturating_sub(1))
+        .any(|section| section.header.is_some() && section.ends_with_blank());
+    for (idx, name) in source_sections.iter().enumerate() {
+        if system_sections.contains(name) {
+            continue;
+        }
+        let Some(from) = sections.iter().position(|s| s.name == *name) else {
+            continue;
+        };
+        let find = |name: &&str| sections.iter().position(|s| s.name == *name);
+        // After the preceding section, or else before the following one
+        let Some(to) = source_sections[..idx]
+            .iter()
+            .rev()
+            .find_map(find)
+            .map(|prev| prev + 1)
+            .or_else(|| source_sections[idx + 1..].iter().find_map(find))
+        else {
+            continue;
+        };
+        let to = if to > from { to - 1 } else { to };
+        let mut section = sections.remove(from);
+        if to < sections.len() && separated && !section.ends_with_blank() {
+            section.lines.push(Line {
+                key: None,
+                raw: String::new(),
+            });
+        }
+        sections.insert(to.min(sections.len()), section);
+    }
+
+    sections
+        .into_iter()
+        .flat_map(|section| {
+            section
+                .header
+                .into_iter()
+                .chain(section.lines.into_iter().map(|line| line.raw))
+        })
+        .collect()
+}
+
+/// Move keys that don't exist in the system state after their preceding
+/// neighbour in the source file. All keys in new sections are moved.
+fn reorder_keys(section: &mut Section, is_new: bool, system: &IniState, source: &IniState) {
+    let order: Vec<_> = source
+        .keys()
+        .filter(|(s, _)| *s == section.name)
+        .map(|(_, key)| key)
+        .collect();
+    let is_inserted =
+        |key: &str| (is_new || !system.has_key(&section.name, key)) && order.contains(&key);
+    let (mut inserted, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut section.lines)
+        .into_iter()
+        .partition(|line| line.key.as_deref().is_some_and(is_inserted));
+    section.lines = kept;
+    if inserted.is_empty() {
+        return;
+    }
+    inserted.sort_by_key(|line| {
+        order
+            .iter()
+            .position(|key| Some(*key) == line.key.as_deref())
+    });
+    for line in inserted {
+        let key = line.key.as_deref().unwrap_or_default();
+        let idx = order.iter().position(|k| *k == key).unwrap_or_default();
+        let pos = order[..idx]
+            .iter()
+            .rev()
+            .find_map(|prev| section.position(prev))
+            .map_or_else(
+                || {
+                    // First in the source: before all other keys, or before
+                    // any trailing blank lines if there are no keys.
+                    section
+                        .lines
+                        .iter()
+                        .position(|line| line.key.is_some())
+                        .unwrap_or_else(|| {
+                            section.lines.len()
+                                - section
+                                    .lines
+                                    .iter()
+                                    .rev()
+                                    .take_while(|line| line.raw.trim().is_empty())
+                                    .count()
+                        })
+                },
+                |prev| prev + 1,
+            );
+        section.lines.insert(pos, line);
+    }
+}
+
+/// Split merged output into sections
+fn parse(merged: &str) -> Vec<Section> {
+    let mut sections = vec![Section {
+        name: OUTSIDE_SECTION.into(),
+        header: None,
+        lines: vec![],
+    }];
+    for item in ini_roundtrip::Parser::new(merged) {
+        let line = match item {
+            Item::SectionEnd => continue,
+            Item::Section { name, raw } => {
+                sections.push(Section {
+                    name: name.into(),
+                    header: Some(raw.into()),
+                    lines: vec![],
+                });
+                continue;
+            }
+            Item::Property { key, raw, .. } => Line {
+                key: Some(key.into()),
+                raw: raw.into(),
+            },
+            Item::Error(raw) | Item::Comment { raw } | Item::Blank { raw } => Line {
+                key: None,
+                raw: raw.into(),
+            },
+        };
+        if let Some(section) = sections.last_mut() {
+            section.lines.push(line);
+        }
+    }
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    fn check(merged: &str, system: &str, source: &str) -> String {
+        let merged: Vec<_> = merged.lines().map(str::to_owned).collect();
+        reorder(&merged, system, source).join("\n") + "\n"
+    }
+
+    #[test]
+    fn check_empty() {
+        assert_eq!(
+            reorder(&[], "[a]\nb=1\n", "[a]\nb=1\n"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn check_keys() {
+        let system = indoc! {"
+            top=1
+            [a]
+            b=1
+            d=1
+
+            [e]
+            f=1
+        "};
+        let source = indoc! {"
+            new_top=1
+            top=1
+            [a]
+            z=2
+            b=2
+            c=2
+            d=2
+            [e]
+            f=2
+            g=2
+        "};
+        // As ini-merge emits it
+        let merged = indoc! {"
+            top=1
+            new_top=1
+            [a]
+            b=2
+            d=2
+
+            c=2
+            forced=3
+            z=2
+            [e]
+            f=2
+            g=2
+        "};
+        assert_eq!(
+            check(merged, system, source),
+            indoc! {"
+                new_top=1
+                top=1
+                [a]
+                z=2
+                b=2
+                c=2
+                d=2
+
+                forced=3
+                [e]
+                f=2
+                g=2
+            "}
+        );
+    }
+
+    #[test]
+    fn check_sections() {
+        let system = indoc! {"
+            [a]
+            k=1
+
+            [d]
+            k=1
+        "};
+        let source = indoc! {"
+            [new first]
+            y=2
+            x=2
+            [a]
+            k=2
+            [c]
+            k=2
+            [b]
+            k=2
+            [d]
+            k=2
+            [e]
+            k=2
+        "};
+        let merged = indoc! {"
+            [a]
+            k=2
+
+            [d]
+            k=2
+            [b]
+            k=2
+            [c]
+            k=2
+            [e]
+            k=2
+            [new first]
+            x=2
+            y=2
+        "};
+        assert_eq!(
+            check(merged, system, source),
+            indoc! {"
+                [new first]
+                y=2
+                x=2
+
+                [a]
+                k=2
+
+                [c]
+                k=2
+
+                [b]
+                k=2
+
+                [d]
+                k=2
+                [e]
+                k=2
+            "}
+        );
+    }
+
+    #[test]
+    fn check_unchanged() {
+        let system = "[a]\nb=1\n[c]\nd=1\n";
+        assert_eq!(check(system, system, "[c]\nd=1\n[a]\nb=1\n"), system);
+    }
+}
@@ -0,0 +1,164 @@
+//! Merging without chezmoi (the `merge` subcommand).
+//!
+//! This uses the same rules and merge engine as modify scripts, but takes
+//! explicit paths for the rules, source and system files, for use from other
+//! tools (such as Ansible or shell scripts).
+
+use crate::MergeOptions;
+use crate::config;
+use crate::utils;
+use anyhow::Context;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use std::io::ErrorKind;
+use std::io::Write;
+
+/// Where to write the merged result
+#[derive(Debug)]
+pub(crate) enum Destination {
+    Stdout,
+    /// A file, which is replaced atomically
+    File(Utf8PathBuf),
+}
+
+/// Merge `source_path` into `system_path` according to the rules file.
+///
+/// A missing system file is treated as empty, like chezmoi does for modify
+/// scripts.
+pub(crate) fn merge<W: Write>(
+    rules_path: &Utf8Path,
+    source_path: &Utf8Path,
+    system_path: &Utf8Path,
+    destination: Destination,
+    stdout: impl FnOnce() -> W,
+) -> anyhow::Result<()> {
+    let rules = std::fs::read_to_string(rules_path)
+        .with_context(|| format!("Failed to load {rules_path}"))?;
+    let include_root = rules_path.parent().unwrap_or_else(|| Utf8Path::new(""));
+    let c = config::parse_for_standalone_merge(&rules, rules_path, include_root, source_path)
+        .with_context(|| format!("Failed to parse {rules_path}"))?;
+    let system = match std::fs::read(system_path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => vec![],
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read system state from {system_path}"));
+        }
+    };
+    let output = crate::merge_file(c, rules_path, &system, MergeOptions::default())?;
+    match destination {
+        Destination::Stdout => stdout().write_all(&output)?,
+        Destination::File(path) => utils::write_atomic(&path, &output)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    fn setup() -> (tempfile::TempDir, Utf8PathBuf) {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        std::fs::write(root.join("rules"), "ignore \"a\" \"kept\"\n").unwrap();
+        std::fs::write(root.join("src.ini"), "[a]\nkept=1\nb=2\n").unwrap();
+        (tmp_dir, root)
+    }
+
+    #[test]
+    fn check_merge() {
+        let (_tmp_dir, root) = setup();
+        std::fs::write(root.join("sys.ini"), "[a]\nkept=3\nb=1\nc=1\n").unwrap();
+        let mut out = vec![];
+        merge(
+            &root.join("rules"),
+            &root.join("src.ini"),
+            &root.join("sys.ini"),
+            Destination::Stdout,
+            || &mut out,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc! {"
+                [a]
+                kept=3
+                b=2
+            "}
+        );
+
+        merge(
+            &root.join("rules"),
+            &root.join("src.ini"),
+            &root.join("sys.ini"),
+            Destination::File(root.join("sys.ini")),
+            Vec::new,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("sys.ini")).unwrap(),
+            "[a]\nkept=3\nb=2\n"
+        );
+    }
+
+    #[test]
+    fn check_missing_system() {
+        let (_tmp_dir, root) = setup();
+        merge(
+            &root.join("rules"),
+            &root.join("src.ini"),
+            &root.join("missing.ini"),
+            Destination::File(root.join("out.ini")),
+            Vec::new,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("out.ini")).unwrap(),
+            "[a]\nb=2\n"
+        );
+        assert!(!root.join("missing.ini").exists());
+    }
+
+    #[test]
+    fn check_on_error() {
+        let (_tmp_dir, root) = setup();
+        std::fs::write(root.join("rules"), "on-error passthrough\n").unwrap();
+        std::fs::write(root.join("sys.ini"), "[a]\nb=1\n").unwrap();
+        merge(
+            &root.join("rules"),
+            &root.join("missing.ini"),
+            &root.join("sys.ini"),
+            Destination::File(root.join("out.ini")),
+            Vec::new,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("out.ini")).unwrap(),
+            "[a]\nb=1\n"
+        );
+
+        // Mistakes in the rules always fail
+        for rule in [
+            "ignore regex \"(\" \"x\"",
+            "transform \"a\" \"b\" no-such-transform",
+        ] {
+            std::fs::write(
+                root.join("rules"),
+                format!("on-error passthrough\n{rule}\n"),
+            )
+            .unwrap();
+            let err = merge(
+                &root.join("rules"),
+                &root.join("src.ini"),
+                &root.join("sys.ini"),
+                Destination::File(root.join("out2.ini")),
+                Vec::new,
+            )
+            .unwrap_err();
+            assert!(format!("{err:#}").contains("rules:2: Invalid"));
+            assert!(!root.join("out2.ini").exists());
+        }
+    }
+}
@@ -7,6 +7,8 @@ use camino::Utf8PathBuf;
 use duct::cmd;
 use regex::Regex;
 use std::env::VarError;
+use std::io::ErrorKind;
+use std::io::Write;
 
 /// Represents the version number of chezmoi
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -149,9 +151,113 @@ impl Chezmoi for RealChezmoi {
     }
 }
 
+/// A per-user base directory: `$<xdg_var>` (or `~/<home_fallback>`) on Unix,
+/// `%LOCALAPPDATA%` on Windows. Empty variables count as unset.
+pub(crate) fn user_dir(xdg_var: &str, home_fallback: &str) -> Option<Utf8PathBuf> {
+    let non_empty = |var| std::env::var(var).ok().filter(|v: &String| !v.is_empty());
+    if cfg!(windows) {
+        non_empty("LOCALAPPDATA").map(Utf8PathBuf::from)
+    } else {
+        non_empty(xdg_var)
+            .map(Utf8PathBuf::from)
+            .or_else(|| Some(Utf8PathBuf::from(non_empty("HOME")?).join(home_fallback)))
+    }
+}
+
+/// Hex encoded SHA-256 hash
+pub(crate) fn sha256_hex(data: &str) -> String {
+    use sha2::Digest;
+    use std::fmt::Write;
+    sha2::Sha256::digest(data.as_bytes()).iter().fold(
+        String::with_capacity(64),
+        |mut result, byte| {
+            _ = write!(result, "{byte:02x}");
+            result
+        },
+    )
+}
+
+/// Write a file by writing a temporary file next to it and renaming that
+/// into place, so that a partially written file is never seen.
+///
+/// The permissions of an existing file are kept, and the file isn't touched
+/// at all if the contents are unchanged. New files get the default
+/// permissions (according to the umask).
+pub(crate) fn write_atomic(path: &Utf8Path, contents: &[u8]) -> anyhow::Result<()> {
+    let permissions = match std::fs::metadata(path) {
+        Ok(metadata) => {
+            if std::fs::read(path).is_ok_and(|existing| existing == contents) {
+                return Ok(());
+            }
+            Some(metadata.permissions())
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => return Err(err).with_context(|| format!("Failed to access {path}")),
+    };
+    let mut builder = tempfile::Builder::new();
+    #[cfg(unix)]
+    if permissions.is_none() {
+        use std::os::unix::fs::PermissionsExt;
+        // Like std::fs::write, the umask applies
+        builder.permissions(std::fs::Permissions::from_mode(0o666));
+    }
+    replace_file(path, contents, &builder, permissions)
+}
+
+/// Like [`write_atomic`], but the file is only accessible by the owner
+/// (regardless of the permissions of an existing file). For caches and state
+/// that may contain secrets.
+pub(crate) fn write_private(path: &Utf8Path, contents: &[u8]) -> anyhow::Result<()> {
+    // Temporary files are created owner-only
+    replace_file(path, contents, &tempfile::Builder::new(), None)
+}
+
+/// Create a directory (and its parents) that is only accessible by the owner.
+/// Existing directories are left as they are.
+pub(crate) fn create_private_dir(dir: &Utf8Path) -> anyhow::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder
+        .create(dir)
+        .with_context(|| format!("Failed to create directory {dir}"))
+}
+
+/// Write `contents` to a temporary file from `builder` next to `path`, and
+/// rename it over `path`.
+fn replace_file(
+    path: &Utf8Path,
+    contents: &[u8],
+    builder: &tempfile::Builder<'_, '_>,
+    permissions: Option<std::fs::Permissions>,
+) -> anyhow::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_str().is_empty() => dir,
+        _ => Utf8Path::new("."),
+    };
+    let mut file = builder
+        .tempfile_in(dir)
+        .with_context(|| format!("Failed to create temporary file in {dir}"))?;
+    file.write_all(contents)
+        .with_context(|| format!("Failed to write temporary file for {path}"))?;
+    if let Some(permissions) = permissions {
+        file.as_file()
+            .set_permissions(permissions)
+            .with_context(|| format!("Failed to set permissions for {path}"))?;
+    }
+    file.persist(path)
+        .with_context(|| format!("Failed to replace {path}"))?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::ChezmoiVersion;
+    use super::create_private_dir;
+    use super::write_atomic;
+    use super::write_private;
+    use camino::Utf8PathBuf;
 
     #[test]
     fn test_chezmoi_version() {
@@ -164,4 +270,52 @@ mod tests {
         assert_eq!(version2, ChezmoiVersion(2, 46, 1));
         assert!(version1 < version2);
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_write_atomic_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        let path = root.join("file");
+        std::fs::write(&path, "old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+
+        // New files get the same permissions as from a plain write
+        let reference = root.join("reference");
+        std::fs::write(&reference, "new").unwrap();
+        let new = root.join("new");
+        write_atomic(&new, b"new").unwrap();
+        assert_eq!(std::fs::read_to_string(&new).unwrap(), "new");
+        assert_eq!(
+            std::fs::metadata(&new).unwrap().permissions().mode(),
+            std::fs::metadata(&reference).unwrap().permissions().mode()
+        );
+        let leftover: Vec<_> = std::fs::read_dir(&root).unwrap().collect();
+        assert_eq!(leftover.len(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_write_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root: Utf8PathBuf = tmp_dir.path().to_path_buf().try_into().unwrap();
+        let dir = root.join("a/b");
+        create_private_dir(&dir).unwrap();
+        let mode = |path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir), 0o700);
+
+        let path = dir.join("file");
+        write_private(&path, b"secret").unwrap();
+        assert_eq!(mode(&path), 0o600);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_private(&path, b"secret").unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "secret");
+    }
 }
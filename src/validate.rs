@@ -0,0 +1,183 @@
+//! Validation of the merged output before it is written (`validate`).
+//!
+//! Applications may reject (or silently reset) a file that is malformed, so
+//! the merged result is parsed again and checked against the input files.
+//! Only problems that merging could have introduced are reported: anything
+//! already present in the system state or the source file is accepted.
+
+use crate::config::Trace;
+use anyhow::anyhow;
+use camino::Utf8Path;
+use ini_merge::OUTSIDE_SECTION;
+use ini_roundtrip::Item;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// The structure of an INI file, as far as validation is concerned
+#[derive(Debug, Default)]
+struct Shape<'a> {
+    /// Sections with more than one header
+    duplicate_sections: BTreeSet<&'a str>,
+    /// All section and key pairs
+    keys: HashSet<(&'a str, &'a str)>,
+    /// Whether there are keys before the first section header
+    keys_outside_sections: bool,
+    /// Lines that can't be parsed
+    errors: Vec<&'a str>,
+}
+
+impl<'a> Shape<'a> {
+    fn new(contents: &'a str) -> Self {
+        let mut result = Self::default();
+        let mut headers: HashMap<&str, usize> = HashMap::new();
+        let mut cur_section = OUTSIDE_SECTION;
+        for item in ini_roundtrip::Parser::new(contents) {
+            match item {
+                Item::Section { name, .. } => {
+                    let count = headers.entry(name).or_default();
+                    *count += 1;
+                    if *count > 1 {
+                        result.duplicate_sections.insert(name);
+                    }
+                    cur_section = name;
+                }
+                Item::Property { key, .. } => {
+                    if cur_section == OUTSIDE_SECTION {
+                        result.keys_outside_sections = true;
+                    }
+                    result.keys.insert((cur_section, key));
+                }
+                Item::Error(line) => result.errors.push(line),
+                Item::SectionEnd | Item::Comment { .. } | Item::Blank { .. } => {}
+            }
+        }
+        result
+    }
+}
+
+/// Check the merged output, with an error listing all problems found.
+///
+/// The `system` contents must already have the renames applied.
+pub(crate) fn check(
+    script_path: &Utf8Path,
+    system: &str,
+    source: &str,
+    merged: &[String],
+    trace: &Trace,
+) -> anyhow::Result<()> {
+    let problems = problems(system, source, &merged.join("\n"), trace);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "{script_path}: The merged result is invalid (use `validate off` to skip this check):\n{}",
+        problems
+            .iter()
+            .map(|problem| format!("  {problem}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    ))
+}
+
+/// Describe all problems with the merged output
+fn problems(system: &str, source: &str, merged: &str, trace: &Trace) -> Vec<String> {
+    let inputs = [Shape::new(system), Shape::new(source)];
+    let output = Shape::new(merged);
+    let mut result = vec![];
+
+    for line in &output.errors {
+        if !inputs.iter().any(|input| input.errors.contains(line)) {
+            result.push(format!("Malformed line: `{line}`"));
+        }
+    }
+    for section in &output.duplicate_sections {
+        if !inputs
+            .iter()
+            .any(|input| input.duplicate_sections.contains(section))
+        {
+            result.push(format!("Section [{section}] occurs more than once"));
+        }
+    }
+    let mut set_keys: Vec<_> = trace.set_keys().collect();
+    set_keys.sort_unstable();
+    for (section, key) in &set_keys {
+        if !output.keys.contains(&(*section, *key)) {
+            result.push(format!(
+                "Key set by `set \"{section}\" \"{key}\"` is missing"
+            ));
+        }
+    }
+    if output.keys_outside_sections
+        && !inputs.iter().any(|input| input.keys_outside_sections)
+        && !set_keys
+            .iter()
+            .any(|(section, _)| *section == OUTSIDE_SECTION)
+    {
+        result.push("Keys outside of any section".into());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use pretty_assertions::assert_eq;
+
+    /// Run the checks with the trace from the given rules
+    fn run(rules: &str, system: &str, source: &str, merged: &str) -> Vec<String> {
+        let rules = format!("source auto\n{rules}");
+        let c =
+            config::parse_for_merge(&rules, Utf8Path::new("modify_x"), Utf8Path::new("")).unwrap();
+        let (_, trace) = c.mutations.build_traced(system, source).unwrap();
+        problems(system, source, merged, &trace)
+    }
+
+    #[test]
+    fn check_valid() {
+        let source = "top=1\n[a]\nb=1\n[a]\nc=2\n[broken\n";
+        assert!(run("", "", source, source).is_empty());
+        assert!(run("set \"a\" \"d\" \"3\"", "", "[a]\n", "[a]\nd = 3\n").is_empty());
+        // Set keys in removed sections don't have to exist
+        let rules = "remove section \"a\"\nset \"a\" \"d\" \"3\"";
+        assert!(run(rules, "[a]\nb=1\n", "", "").is_empty());
+    }
+
+    #[test]
+    fn check_problems() {
+        let problems = run(
+            "set \"a\" \"d\" \"3\"\nset \"b\" \"e\" \"4\"",
+            "[a]\nb=1\n",
+            "[a]\nb=2\n",
+            "top=1\n[a]\nb=2\n[broken\n[a]\nd = 3\n",
+        );
+        assert_eq!(
+            problems,
+            vec![
+                "Malformed line: `[broken`",
+                "Section [a] occurs more than once",
+                "Key set by `set \"b\" \"e\"` is missing",
+                "Keys outside of any section",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_error() {
+        let merged = vec!["[a]".to_owned(), "[a]".to_owned()];
+        let err = check(
+            Utf8Path::new("modify_x"),
+            "",
+            "",
+            &merged,
+            &Trace::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "modify_x: The merged result is invalid (use `validate off` to skip this check):\n  \
+             Section [a] occurs more than once"
+        );
+    }
+}
@@ -45,7 +45,13 @@ fn test_data() {
         let mut status: Vec<u8> = vec![];
 
         inner_main(
-            ChmmArgs::Process(test_case),
+            ChmmArgs::Process {
+                strict: false,
+                diff: false,
+                no_cache: true,
+                system: None,
+                file: test_case,
+            },
             || BufReader::new(File::open(&sys).unwrap()),
             || &mut stdout,
             || &mut status,